@@ -1,71 +1,86 @@
 use clap::Parser;
-use serde::{Deserialize, Serialize};
-use std::time::{SystemTime, UNIX_EPOCH};
+use final_project::congestion::Cc;
+use final_project::dedup::WINDOW;
+use final_project::file::FileSource;
+use final_project::retry::{Backoff, Jitter};
+use final_project::sender::Arq;
+use final_project::{DeliveryError, ReliableSender, SenderConfig};
+use std::path::PathBuf;
+use std::pin::Pin;
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
-    net::{TcpStream, UdpSocket},
-    sync::mpsc,
-    time::{Duration, timeout},
+    fs::File,
+    io::{AsyncBufReadExt, AsyncRead, BufReader},
+    time::{Duration, Instant, sleep_until},
 };
 
 /*
- * Reliability Mechanism:
- * assign a seq number to each message
- * send the message to the server and wait for ack
- * retransmit if no ack within timeout period
- * after a maximum num of retries, give up on that message and error
+ * Client:
+ * reads lines from stdin and sends each one to the server as a message
+ * through a ReliableSender (src/sender.rs describes the protocol:
+ * handshake, Go-Back-N / Selective Repeat, adaptive RTO, retry backoff,
+ * congestion control, pacing, outbox, dead letters, heartbeats, failover)
+ *
+ * File transfer (--file):
+ * instead of stdin lines, the file is sent as chunks sized so each
+ * datagram fits in --mtu bytes (at most 65507, the largest UDP datagram
+ * over IPv4); every chunk carries the file name,
+ * size, whole-file SHA-256 and its offset so the server can reassemble
+ * and verify it
+ *
+ * Fragmentation:
+ * a line too long for one --mtu sized datagram is split into fragments
+ * that take consecutive seqs; the server delivers it as one message
+ *
+ * Batch mode (--input file, or --batch for piped stdin):
+ * no prompt, lines are optionally paced at --rate msgs/sec, and at
+ * exit a JSON summary (delivered, failed, transmissions, retransmission
+ * ratio, RTT min/avg/p50/p95/p99 in ms) is printed and, with
+ * --summary, written to a file
+ *
+ * At the end of input we wait until every message is acked or given up,
+ * close the session and print a delivery report
  *
  * Args:
  * --target-ip
  * --target-port
+ * --target (ip:port, repeatable, tried in order)
  * --timeout
  * --max-retries
- *
- * One Server Max at a time
- * No connection/handshake logic
+ * --window (at most 1024, the server's dedup window)
+ * --arq
+ * --min-rto-ms
+ * --max-rto-ms
+ * --backoff
+ * --backoff-cap-ms
+ * --jitter
+ * --cc
+ * --initial-window
+ * --ssthresh
+ * --pace
+ * --pace-burst
+ * --file
+ * --mtu (alias --max-datagram)
+ * --input
+ * --batch
+ * --rate
+ * --summary
+ * --outbox
+ * --dead-letter
+ * --heartbeat-interval-ms
+ * --heartbeat-misses
 */
 
-#[derive(Serialize)]
-struct Message {
-    msg: String,
-    seq: u64,
-}
-
-#[derive(Deserialize)]
-struct Ack {
-    seq: u64,
-}
-
-#[derive(Serialize)]
-struct LogEvent {
-    ts: f64,
-    component: &'static str,
-    event: &'static str,
-    seq: u64,
-}
-
-fn timestamp() -> f64 {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs_f64()
-}
-
-async fn log_task(mut stream: TcpStream, mut rx: mpsc::Receiver<LogEvent>) {
-    while let Some(ev) = rx.recv().await {
-        let line = serde_json::to_string(&ev).unwrap() + "\n";
-        let _ = stream.write_all(line.as_bytes()).await;
-    }
-}
-
 #[derive(Parser, Debug)]
 #[command(version, about, long_about=None)]
 struct Args {
     #[arg(long)]
-    target_ip: String,
+    target_ip: Option<String>,
+
+    #[arg(long)]
+    target_port: Option<u16>,
 
     #[arg(long)]
-    target_port: u16,
+    target: Vec<String>,
 
     #[arg(long)]
     timeout: u64,
@@ -78,122 +93,208 @@ struct Args {
 
     #[arg(long)]
     log_port: u16,
-}
 
-/**
-Main function to act as the driver for the client
-**/
-#[tokio::main]
-async fn main() -> tokio::io::Result<()> {
-    let args = Args::parse();
+    #[arg(long, default_value_t = 1)]
+    window: usize,
 
-    let server_addr = format!("{}:{}", args.target_ip, args.target_port); // <-- change
-    let bind_addr = "0.0.0.0:0"; // client UDP port
+    #[arg(long, value_enum, default_value_t = Arq::GoBackN)]
+    arq: Arq,
 
-    let log_addr = format!("{}:{}", args.log_host, args.log_port); // UI log stream (client channel)
+    #[arg(long, default_value_t = 50)]
+    min_rto_ms: u64,
 
-    let udp = UdpSocket::bind(bind_addr).await?;
-    udp.connect(server_addr).await?;
+    #[arg(long, default_value_t = 60_000)]
+    max_rto_ms: u64,
 
-    let (log_tx, log_rx) = mpsc::channel::<LogEvent>(1000);
+    #[arg(long, value_enum, default_value_t = Backoff::Fixed)]
+    backoff: Backoff,
 
-    tokio::spawn(async move {
-        let stream = TcpStream::connect(log_addr).await.unwrap();
-        log_task(stream, log_rx).await;
-    });
+    #[arg(long, default_value_t = 60_000)]
+    backoff_cap_ms: u64,
 
-    let mut stdin = BufReader::new(tokio::io::stdin()).lines();
-    let mut seq: u64 = 1;
+    #[arg(long, value_enum, default_value_t = Jitter::None)]
+    jitter: Jitter,
 
-    println!("Client ready");
+    #[arg(long, value_enum, default_value_t = Cc::None)]
+    cc: Cc,
 
-    loop {
-        print!("> ");
-        use std::io::Write;
-        std::io::stdout().flush()?;
+    #[arg(long, default_value_t = 1)]
+    initial_window: usize,
 
-        let line = match stdin.next_line().await? {
-            Some(l) => l,
-            None => break,
-        };
+    #[arg(long, default_value_t = 64)]
+    ssthresh: usize,
 
-        if line.trim().is_empty() {
-            continue;
-        }
+    #[arg(long)]
+    pace: Option<f64>,
 
-        let msg = Message {
-            seq,
-            msg: line.clone(),
-        };
+    #[arg(long, default_value_t = 1.0)]
+    pace_burst: f64,
 
-        let encoded = serde_json::to_vec(&msg)?;
+    #[arg(long)]
+    file: Option<PathBuf>,
 
-        // initial send
-        udp.send(&encoded).await?;
-        log_tx
-            .send(LogEvent {
-                ts: timestamp(),
-                component: "client",
-                event: "send",
-                seq,
-            })
-            .await
-            .ok();
+    #[arg(long, alias = "max-datagram", default_value_t = 1400)]
+    mtu: usize,
 
-        // ---- Wait for ACK ----
-        let mut buf = [0u8; 256];
-        let mut tries = 0;
+    #[arg(long)]
+    input: Option<PathBuf>,
 
-        loop {
-            if tries >= args.max_retries {
-                eprintln!(
-                    "ERROR: seq {} failed after {} retries",
-                    seq, args.max_retries
-                );
+    #[arg(long)]
+    batch: bool,
+
+    #[arg(long)]
+    rate: Option<f64>,
+
+    #[arg(long)]
+    summary: Option<PathBuf>,
+
+    #[arg(long)]
+    outbox: Option<PathBuf>,
+
+    #[arg(long)]
+    dead_letter: Option<PathBuf>,
+
+    #[arg(long)]
+    heartbeat_interval_ms: Option<u64>,
+
+    #[arg(long, default_value_t = 3)]
+    heartbeat_misses: u32,
+}
+
+/**
+Main function to act as the driver for the client
+**/
+#[tokio::main]
+async fn main() -> tokio::io::Result<()> {
+    let args = Args::parse();
+
+    let mut targets = Vec::new();
+    if let (Some(ip), Some(port)) = (&args.target_ip, args.target_port) {
+        targets.push(format!("{}:{}", ip, port));
+    }
+    targets.extend(args.target.iter().cloned());
+    if targets.is_empty() {
+        eprintln!("ERROR: give --target-ip and --target-port, or --target ip:port");
+        return Err(std::io::ErrorKind::InvalidInput.into());
+    }
+    if args.window > WINDOW as usize {
+        eprintln!("ERROR: --window can be at most {}", WINDOW);
+        return Err(std::io::ErrorKind::InvalidInput.into());
+    }
+    // the gap between two lines (or datagrams) has to fit in a Duration
+    for (flag, rate) in [("--rate", args.rate), ("--pace", args.pace)] {
+        if let Some(rate) = rate
+            && rate > 0.0
+            && Duration::try_from_secs_f64(1.0 / rate).is_err()
+        {
+            eprintln!("ERROR: {} {:e} is too small", flag, rate);
+            return Err(std::io::ErrorKind::InvalidInput.into());
+        }
+    }
+
+    let config = SenderConfig {
+        targets,
+        timeout: Duration::from_secs(args.timeout),
+        max_retries: args.max_retries,
+        window: args.window,
+        arq: args.arq,
+        min_rto: Duration::from_millis(args.min_rto_ms),
+        max_rto: Duration::from_millis(args.max_rto_ms),
+        backoff: args.backoff,
+        backoff_cap: Duration::from_millis(args.backoff_cap_ms),
+        jitter: args.jitter,
+        cc: args.cc,
+        initial_window: args.initial_window,
+        ssthresh: args.ssthresh,
+        pace: args.pace,
+        pace_burst: args.pace_burst,
+        mtu: args.mtu,
+        outbox: args.outbox.clone(),
+        dead_letter: args.dead_letter.clone(),
+        heartbeat_interval: args.heartbeat_interval_ms.map(Duration::from_millis),
+        heartbeat_misses: args.heartbeat_misses,
+        log_addr: Some(format!("{}:{}", args.log_host, args.log_port)), // UI log stream (client channel)
+        verbose: true,
+    };
+
+    let mut file = match &args.file {
+        Some(path) => Some(FileSource::open(path, args.mtu).await?),
+        None => None,
+    };
+    let batch = args.batch || args.input.is_some();
+
+    let sender = ReliableSender::connect(config).await?;
+
+    if let Some(f) = file.as_mut() {
+        println!(
+            "Sending {} ({} bytes, {} byte chunks)",
+            f.template.name, f.template.size, f.chunk_size
+        );
+        while let Some(chunk) = f.next_chunk().await? {
+            // only fails once the sender has stopped; close() says why
+            if sender.submit_chunk(chunk).await.is_err() {
                 break;
             }
+        }
+    } else {
+        let reader: Pin<Box<dyn AsyncRead>> = match &args.input {
+            Some(path) => Box::pin(File::open(path).await?),
+            None => Box::pin(tokio::io::stdin()),
+        };
+        let mut lines = BufReader::new(reader).lines();
 
-            let recv_result = timeout(Duration::from_secs(args.timeout), udp.recv(&mut buf)).await;
-
-            match recv_result {
-                // ACK received
-                Ok(Ok(n)) => {
-                    let ack: Ack = serde_json::from_slice(&buf[..n])?;
-
-                    if ack.seq == seq {
-                        log_tx
-                            .send(LogEvent {
-                                ts: timestamp(),
-                                component: "client",
-                                event: "ack_recv",
-                                seq,
-                            })
-                            .await
-                            .ok();
-                        println!("ACK for seq {}", seq);
-                        break;
-                    }
-                }
-
-                // Timeout → resend
-                _ => {
-                    udp.send(&encoded).await?;
-                    log_tx
-                        .send(LogEvent {
-                            ts: timestamp(),
-                            component: "client",
-                            event: "send",
-                            seq,
-                        })
-                        .await
-                        .ok();
-                    println!("Timeout, resend seq {}", seq);
-                    tries += 1;
-                }
+        // earliest time the next line may be read when pacing with --rate
+        let line_gap = args
+            .rate
+            .filter(|r| *r > 0.0)
+            .map(|r| Duration::from_secs_f64(1.0 / r));
+        let mut next_line_at = Instant::now();
+
+        if !batch {
+            println!("Client ready");
+        }
+        loop {
+            if let Some(gap) = line_gap {
+                sleep_until(next_line_at).await;
+                next_line_at = next_line_at.max(Instant::now()) + gap;
+            }
+            if !batch {
+                print!("> ");
+                use std::io::Write;
+                std::io::stdout().flush()?;
+            }
+
+            let Some(line) = lines.next_line().await? else {
+                break;
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            match sender.submit(line).await {
+                Ok(_) => {}
+                Err(e @ DeliveryError::TooLong { .. }) => eprintln!("ERROR: {}", e),
+                Err(_) => break,
             }
         }
+    }
 
-        seq += 1;
+    let report = sender.close().await?;
+    let stats = &report.stats;
+    println!(
+        "Session {} {}: {} delivered, {} failed, {} transmissions",
+        report.session,
+        if report.closed { "closed" } else { "left open" },
+        stats.delivered,
+        stats.failed,
+        stats.transmissions
+    );
+
+    if batch || args.summary.is_some() {
+        let summary = serde_json::to_string_pretty(&stats.summary())?;
+        println!("{}", summary);
+        if let Some(path) = &args.summary {
+            tokio::fs::write(path, summary + "\n").await?;
+        }
     }
 
     Ok(())
@@ -5,8 +5,10 @@ use crossterm::{
     execute,
     terminal::{EnterAlternateScreen, LeaveAlternateScreen, disable_raw_mode, enable_raw_mode},
 };
+use final_project::impair::{Impairment, Verdict};
+use final_project::log::{LogEvent, timestamp};
+use rand::SeedableRng;
 use rand::rngs::StdRng;
-use rand::{Rng, SeedableRng};
 use ratatui::{
     Frame, Terminal,
     backend::CrosstermBackend,
@@ -16,8 +18,8 @@ use ratatui::{
     text::Line,
     widgets::{Bar, BarChart, BarGroup, Block},
 };
-use serde::{Deserialize, Serialize};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::{io::stdout, sync::Arc, time::Duration};
 use tokio::fs::OpenOptions;
 use tokio::io::AsyncWriteExt;
@@ -25,14 +27,16 @@ use tokio::{
     io::{AsyncBufReadExt, BufReader},
     net::{TcpListener, TcpStream, UdpSocket},
     sync::Mutex,
-    time::sleep,
+    task::JoinHandle,
+    time::{Instant, sleep},
 };
 
 /*
  * Proxy Server:
  * listen for packets from the client on specified ip port
- * forward packets to actual server
- * listen for packets from the server and forward back to client
+ * forward packets to actual server, from a separate socket per client
+ * (dropped, with its forwarding task, once the client is idle for long enough)
+ * listen for packets from the server and forward back to that client
  * randomly drop packets based on configured drop probabilities
  * randomly delaying packets based on configured delay probabilities
  *
@@ -55,6 +59,13 @@ use tokio::{
  * --client-delay-time-max: maximum delay time for client packets
  * --server-delay-time-min: minimum delay time for server packets
  * --server-delay-time-max: maximum delay time for server packets
+ *
+ * --log-port:      TCP port the client and server stream their log events to
+ * --log-file:      where every log event is appended (proxy.log by default)
+ * --seed:          seed for the drop / delay decisions (the server direction uses seed + 1)
+ * --idle-timeout-secs: forget clients silent for this long (0 keeps them forever);
+ *                  one that comes back reaches the server from a new port
+ * --headless:      no TUI, just forward and log until interrupted (for scripts and tests)
  */
 
 #[derive(Parser, Debug, Clone)]
@@ -98,19 +109,18 @@ struct Args {
 
     #[arg(long)]
     log_port: u16,
-}
 
-#[allow(dead_code)]
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct Message {
-    msg: String,
-    seq: u64,
-}
+    #[arg(long, default_value = "proxy.log")]
+    log_file: String,
+
+    #[arg(long, default_value_t = 42)]
+    seed: u64,
 
-#[allow(dead_code)]
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct Ack {
-    received: u64,
+    #[arg(long, default_value_t = 300)]
+    idle_timeout_secs: u64,
+
+    #[arg(long)]
+    headless: bool,
 }
 
 #[derive(Default, Clone)]
@@ -119,22 +129,16 @@ struct Metrics {
     packets_received: u64, // server recv
     ack_sent: u64,         // server ack_send
     ack_received: u64,     // client ack_recv
+    failed: u64,           // client give_up
+    sessions: u64,         // server sessions (currently open)
 }
 
-#[derive(Deserialize, Serialize, Clone)]
-struct LogEvent {
-    ts: f64,
-    component: String,
-    event: String,
-    seq: Option<u64>,
-}
-
-#[allow(dead_code)]
-fn timestamp() -> f64 {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs_f64()
+/// One client's socket towards the server, and the task sending the
+/// server's replies on that socket back to the client
+struct Upstream {
+    sock: Arc<UdpSocket>,
+    replies: JoinHandle<()>,
+    last_seen: Instant,
 }
 
 #[derive(Clone)]
@@ -156,6 +160,7 @@ impl LogFile {
         let mut f = self.0.lock().await;
         let _ = f.write_all(line.as_bytes()).await;
         let _ = f.write_all(b"\n").await;
+        let _ = f.flush().await;
     }
 }
 
@@ -165,6 +170,7 @@ async fn log_proxy(log_file: &LogFile, event: &str, seq: Option<u64>, component:
         component: component.to_string(),
         event: event.to_string(),
         seq,
+        value: None,
     };
     log_file.write_event(&ev).await;
 }
@@ -173,7 +179,7 @@ async fn log_proxy(log_file: &LogFile, event: &str, seq: Option<u64>, component:
 async fn main() -> tokio::io::Result<()> {
     let args = Args::parse();
 
-    let log_file = LogFile::new("proxy.log").await;
+    let log_file = LogFile::new(&args.log_file).await;
 
     let metrics = Arc::new(Mutex::new(Metrics::default()));
 
@@ -193,112 +199,124 @@ async fn main() -> tokio::io::Result<()> {
 
     let client_sock =
         Arc::new(UdpSocket::bind(format!("{}:{}", args.listen_ip, args.listen_port)).await?);
-    let server_sock = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
-    let server_addr: std::net::SocketAddr = format!("{}:{}", args.target_ip, args.target_port)
+    let server_addr: SocketAddr = format!("{}:{}", args.target_ip, args.target_port)
         .parse()
         .expect("invalid server address");
-    let last_client = Arc::new(Mutex::new(None::<std::net::SocketAddr>));
+
+    let client_impairment = Impairment {
+        drop: args.client_drop,
+        delay: args.client_delay,
+        delay_min: args.client_delay_time_min,
+        delay_max: args.client_delay_time_max,
+    };
+    let server_impairment = Impairment {
+        drop: args.server_drop,
+        delay: args.server_delay,
+        delay_min: args.server_delay_time_min,
+        delay_max: args.server_delay_time_max,
+    };
+    // one rng per direction, shared by all clients
+    let server_rng = Arc::new(Mutex::new(StdRng::seed_from_u64(args.seed.wrapping_add(1))));
 
     {
         // CLIENT -> SERVER
+        // every client gets its own socket towards the server, so the server
+        // sees separate addresses and the replies find their way back
         let client_sock = client_sock.clone();
-        let server_sock = server_sock.clone();
-        let last_client = last_client.clone();
-        let args = Arc::new(args.clone());
-        let mut rng = StdRng::seed_from_u64(42);
+        let mut rng = StdRng::seed_from_u64(args.seed);
+        let mut upstreams: HashMap<SocketAddr, Upstream> = HashMap::new();
+        let idle =
+            (args.idle_timeout_secs > 0).then(|| Duration::from_secs(args.idle_timeout_secs));
 
         let log_file = log_file.clone();
 
         tokio::spawn(async move {
-            let mut buf = vec![0u8; 2048];
+            let mut buf = vec![0u8; 65535];
+            let mut sweep = tokio::time::interval(Duration::from_secs(1));
 
             loop {
-                let (n, client_addr) = match client_sock.recv_from(&mut buf).await {
-                    Ok(res) => res,
-                    Err(_) => continue,
+                let (n, client_addr) = tokio::select! {
+                    res = client_sock.recv_from(&mut buf) => match res {
+                        Ok(res) => res,
+                        Err(_) => continue,
+                    },
+                    _ = sweep.tick(), if idle.is_some() => {
+                        let idle = idle.unwrap();
+                        let before = upstreams.len();
+                        upstreams.retain(|_, up| {
+                            let alive = up.last_seen.elapsed() < idle;
+                            if !alive {
+                                up.replies.abort();
+                            }
+                            alive
+                        });
+                        for _ in upstreams.len()..before {
+                            log_proxy(&log_file, "expire", None, "proxy_client").await;
+                        }
+                        continue;
+                    }
                 };
 
                 log_proxy(&log_file, "recv", None, "proxy_client").await;
 
-                // Remember the client
-                *last_client.lock().await = Some(client_addr);
-
-                // Drop packet?
-                if rng.random::<f64>() < args.client_drop {
-                    log_proxy(&log_file, "drop", None, "proxy_client").await;
-                    continue;
-                }
+                let upstream = match upstreams.get_mut(&client_addr) {
+                    Some(up) => {
+                        up.last_seen = Instant::now();
+                        up.sock.clone()
+                    }
+                    None => {
+                        let Ok(sock) = UdpSocket::bind("0.0.0.0:0").await else {
+                            continue;
+                        };
+                        let sock = Arc::new(sock);
+                        let replies = tokio::spawn(server_to_client(
+                            sock.clone(),
+                            client_sock.clone(),
+                            client_addr,
+                            server_addr,
+                            server_impairment,
+                            server_rng.clone(),
+                            log_file.clone(),
+                        ));
+                        upstreams.insert(
+                            client_addr,
+                            Upstream {
+                                sock: sock.clone(),
+                                replies,
+                                last_seen: Instant::now(),
+                            },
+                        );
+                        sock
+                    }
+                };
 
-                // Delay packet?
-                if rng.random::<f64>() < args.client_delay {
-                    let min = args.client_delay_time_min;
-                    let max = args.client_delay_time_max.max(min);
-                    let delay = if min == max {
-                        min
-                    } else {
-                        rng.random_range(min..=max)
-                    };
-                    log_proxy(&log_file, "delay", None, "proxy_client").await;
-                    sleep(Duration::from_millis(delay)).await;
+                // Drop or delay packet?
+                match client_impairment.judge(&mut rng) {
+                    Verdict::Drop => {
+                        log_proxy(&log_file, "drop", None, "proxy_client").await;
+                        continue;
+                    }
+                    Verdict::Delay(delay) => {
+                        log_proxy(&log_file, "delay", None, "proxy_client").await;
+                        sleep(delay).await;
+                    }
+                    Verdict::Forward => {}
                 }
 
                 // Forward exactly n bytes to server
                 log_proxy(&log_file, "forward", None, "proxy_client").await;
-                let _ = server_sock.send_to(&buf[..n], server_addr).await;
+                let _ = upstream.send_to(&buf[..n], server_addr).await;
             }
         });
     }
 
-    {
-        // SERVER -> CLIENT
-        let server_sock = server_sock.clone();
-        let last_client = last_client.clone();
-        let args = Arc::new(args.clone());
-        let mut rng = StdRng::seed_from_u64(43);
-
-        let log_file = log_file.clone();
-
-        tokio::spawn(async move {
-            let mut buf = vec![0u8; 2048];
-
-            loop {
-                let (n, src_addr) = match server_sock.recv_from(&mut buf).await {
-                    Ok(res) => res,
-                    Err(_) => continue,
-                };
-
-                log_proxy(&log_file, "recv", None, "proxy_server").await;
-
-                if src_addr != server_addr {
-                    continue;
-                }
-
-                // Drop packet?
-                if rng.random::<f64>() < args.server_drop {
-                    log_proxy(&log_file, "drop", None, "proxy_server").await;
-                    continue;
-                }
-
-                // Delay packet?
-                if rng.random::<f64>() < args.server_delay {
-                    let min = args.server_delay_time_min;
-                    let max = args.server_delay_time_max.max(min);
-                    let delay = if min == max {
-                        min
-                    } else {
-                        rng.random_range(min..=max)
-                    };
-                    log_proxy(&log_file, "delay", None, "proxy_server").await;
-                    sleep(Duration::from_millis(delay)).await;
-                }
-
-                // Forward exactly n bytes to last client
-                if let Some(client_addr) = *last_client.lock().await {
-                    log_proxy(&log_file, "forward", None, "proxy_server").await;
-                    let _ = client_sock.send_to(&buf[..n], client_addr).await;
-                }
-            }
-        });
+    if args.headless {
+        println!(
+            "Proxy forwarding {}:{} -> {}",
+            args.listen_ip, args.listen_port, server_addr
+        );
+        tokio::signal::ctrl_c().await?;
+        return Ok(());
     }
 
     // ratatui
@@ -319,12 +337,12 @@ async fn main() -> tokio::io::Result<()> {
         })?;
 
         // Exit on q
-        if event::poll(Duration::from_millis(100))? {
-            if let Event::Key(key) = event::read()? {
-                if key.kind == KeyEventKind::Press && key.code == KeyCode::Char('q') {
-                    break;
-                }
-            }
+        if event::poll(Duration::from_millis(100))?
+            && let Event::Key(key) = event::read()?
+            && key.kind == KeyEventKind::Press
+            && key.code == KeyCode::Char('q')
+        {
+            break;
         }
     }
 
@@ -337,6 +355,51 @@ async fn main() -> tokio::io::Result<()> {
     Ok(())
 }
 
+/// SERVER -> CLIENT for one client: whatever the server sends to this
+/// client's upstream socket goes back to it
+async fn server_to_client(
+    upstream: Arc<UdpSocket>,
+    client_sock: Arc<UdpSocket>,
+    client_addr: SocketAddr,
+    server_addr: SocketAddr,
+    impairment: Impairment,
+    rng: Arc<Mutex<StdRng>>,
+    log_file: LogFile,
+) {
+    let mut buf = vec![0u8; 65535];
+
+    loop {
+        let (n, src_addr) = match upstream.recv_from(&mut buf).await {
+            Ok(res) => res,
+            Err(_) => continue,
+        };
+
+        log_proxy(&log_file, "recv", None, "proxy_server").await;
+
+        if src_addr != server_addr {
+            continue;
+        }
+
+        // Drop or delay packet?
+        let verdict = impairment.judge(&mut *rng.lock().await);
+        match verdict {
+            Verdict::Drop => {
+                log_proxy(&log_file, "drop", None, "proxy_server").await;
+                continue;
+            }
+            Verdict::Delay(delay) => {
+                log_proxy(&log_file, "delay", None, "proxy_server").await;
+                sleep(delay).await;
+            }
+            Verdict::Forward => {}
+        }
+
+        // Forward exactly n bytes to the client
+        log_proxy(&log_file, "forward", None, "proxy_server").await;
+        let _ = client_sock.send_to(&buf[..n], client_addr).await;
+    }
+}
+
 async fn handle_log(stream: TcpStream, metrics: Arc<Mutex<Metrics>>, log_file: LogFile) {
     let reader = BufReader::new(stream);
     let mut lines = reader.lines();
@@ -349,8 +412,10 @@ async fn handle_log(stream: TcpStream, metrics: Arc<Mutex<Metrics>>, log_file: L
             match (log.component.as_str(), log.event.as_str()) {
                 ("client", "send") => m.packets_sent += 1,
                 ("client", "ack_recv") => m.ack_received += 1,
+                ("client", "give_up") => m.failed += 1,
                 ("server", "recv") => m.packets_received += 1,
                 ("server", "ack_send") => m.ack_sent += 1,
+                ("server", "sessions") => m.sessions = log.value.unwrap_or(0.0) as u64,
                 _ => {}
             }
         }
@@ -363,6 +428,8 @@ fn draw_tui(f: &mut Frame<'_>, m: &Metrics) {
         ("Recv", m.packets_received),
         ("ACK Sent", m.ack_sent),
         ("ACK Recv", m.ack_received),
+        ("Failed", m.failed),
+        ("Sessions", m.sessions),
     ];
 
     let max_val = values.iter().map(|(_, v)| *v).max().unwrap_or(1);
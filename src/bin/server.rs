@@ -1,14 +1,23 @@
 use clap::Parser;
-use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
-use std::time::{SystemTime, UNIX_EPOCH};
-use tokio::io::AsyncWriteExt;
-use tokio::net::{TcpStream, UdpSocket};
-
-//use serde::{Deserialize, Serialize};
+use final_project::{Delivery, ReceiverConfig, ReliableReceiver};
+use std::path::PathBuf;
+use tokio::time::Duration;
 
 /*
  * Listens on udp socket and receives messages from client
+ * through a ReliableReceiver (src/receiver.rs describes the protocol:
+ * sessions and epochs, duplicate detection, cum / sack acks,
+ * heartbeats and teardown)
+ *
+ * File transfer:
+ * file chunks carry the file name, total size, whole-file SHA-256 and
+ * their byte offset; they are written into --output-dir/<name>.<hash>.part
+ * (<hash>: the first 8 hex digits of the SHA-256) and once every byte is
+ * in, the file is hashed and renamed to <name> if the hash matches
+ *
+ * Fragments:
+ * a long message arrives as frag_count fragments on consecutive seqs;
+ * they are held until all of them are in and then printed as one message
  *
  * Result:
  * prints message to standard output
@@ -17,8 +26,19 @@ use tokio::net::{TcpStream, UdpSocket};
  * Args:
  * --listen-ip:     ip address to bind
  * --listen-port:   UDP port to listen on
+ * --output-dir:    where received files are written
+ * --heartbeat-interval-ms: client liveness check interval (off by default)
+ * --heartbeat-misses:      silent intervals before a client counts as down
+ * --idle-timeout-secs:     drop sessions silent for this long (0 keeps them forever)
+ * --ordered:               print messages in seq order, holding early ones back
+ * --reorder-limit:         early messages held per session before skipping a gap
+ *                          (0 holds none: every gap is skipped at once)
+ * --journal:               append every received message to this file, synced before the ack;
+ *                          on restart it is replayed so nothing acked is printed twice
  *
- * Handles one client at a time and is not required to support concurrent connections
+ * Serves any number of clients at once: every (client address, session)
+ * has its own dedup state and counters, and the number of open sessions
+ * goes to the log stream as a sessions event
 */
 
 #[derive(Parser, Debug)]
@@ -35,95 +55,86 @@ struct Args {
 
     #[arg(long)]
     log_port: u16,
-}
 
-#[derive(Deserialize)]
-struct Message {
-    msg: String,
-    seq: u64,
-}
+    #[arg(long, default_value = ".")]
+    output_dir: PathBuf,
 
-#[derive(Serialize)]
-struct Ack {
-    seq: u64,
-}
+    #[arg(long)]
+    heartbeat_interval_ms: Option<u64>,
 
-#[derive(Serialize)]
-struct LogEvent {
-    ts: f64,
-    component: String,
-    event: String,
-    seq: Option<u64>,
-}
+    #[arg(long, default_value_t = 3)]
+    heartbeat_misses: u32,
+
+    #[arg(long, default_value_t = 300)]
+    idle_timeout_secs: u64,
 
-fn timestamp() -> f64 {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs_f64()
+    #[arg(long)]
+    ordered: bool,
+
+    #[arg(long, default_value_t = 256)]
+    reorder_limit: usize,
+
+    #[arg(long)]
+    journal: Option<PathBuf>,
 }
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
     let args = Args::parse();
 
-    let server_addr = format!("{}:{}", args.listen_ip, args.listen_port);
-    let udp = UdpSocket::bind(server_addr).await?;
+    let config = ReceiverConfig {
+        listen: format!("{}:{}", args.listen_ip, args.listen_port),
+        output_dir: args.output_dir,
+        heartbeat_interval: args.heartbeat_interval_ms.map(Duration::from_millis),
+        heartbeat_misses: args.heartbeat_misses,
+        idle_timeout: Some(Duration::from_secs(args.idle_timeout_secs)),
+        ordered: args.ordered,
+        reorder_limit: args.reorder_limit,
+        journal: args.journal,
+        log_addr: Some(format!("{}:{}", args.log_host, args.log_port)),
+        verbose: true,
+    };
+    let mut receiver = ReliableReceiver::bind(config).await?;
 
     println!("Server listening on {}...", args.listen_port);
 
-    let log_addr = format!("{}:{}", args.log_host, args.log_port);
-    let log_stream = TcpStream::connect(log_addr).await?;
-    let log_stream = tokio::sync::Mutex::new(log_stream);
-
-    let mut received: HashSet<u64> = HashSet::new();
-    let mut buf = [0u8; 2048];
-
     loop {
-        let (n, addr) = udp.recv_from(&mut buf).await?;
-
-        let msg: Message = match serde_json::from_slice(&buf[..n]) {
-            Ok(m) => m,
-            Err(_) => continue,
-        };
-
-        send_log(
-            &log_stream,
-            LogEvent {
-                ts: timestamp(),
-                component: "server".to_string(),
-                event: "recv".to_string(),
-                seq: Some(msg.seq),
-            },
-        )
-        .await;
-
-        if received.contains(&msg.seq) {
-            println!("Duplicate seq {} ignored", msg.seq);
-        } else {
-            println!("Got msg='{}' seq={} from {}", msg.msg, msg.seq, addr);
-            received.insert(msg.seq);
+        match receiver.recv().await? {
+            Delivery::Message {
+                from,
+                seq,
+                fragments,
+                data,
+                ..
+            } => {
+                let text = String::from_utf8_lossy(&data);
+                if fragments > 1 {
+                    println!(
+                        "Got msg='{}' seq={}..={} ({} fragments) from {}",
+                        text,
+                        seq,
+                        seq + fragments as u64 - 1,
+                        fragments,
+                        from
+                    );
+                } else {
+                    println!("Got msg='{}' seq={} from {}", text, seq, from);
+                }
+            }
+            Delivery::File {
+                path,
+                size,
+                verified: true,
+                ..
+            } => println!(
+                "File {} received ({} bytes), SHA-256 verified",
+                path.display(),
+                size
+            ),
+            Delivery::File { path, .. } => eprintln!(
+                "ERROR: file {} failed its SHA-256 check, kept as .part",
+                path.display()
+            ),
         }
-
-        let ack = Ack { seq: msg.seq };
-        let encoded = serde_json::to_vec(&ack).unwrap();
-        udp.send_to(&encoded, addr).await?;
-
-        send_log(
-            &log_stream,
-            LogEvent {
-                ts: timestamp(),
-                component: "server".to_string(),
-                event: "ack_send".to_string(),
-                seq: Some(msg.seq),
-            },
-        )
-        .await;
     }
 }
-
-async fn send_log(stream: &tokio::sync::Mutex<TcpStream>, log: LogEvent) {
-    let data = serde_json::to_string(&log).unwrap() + "\n";
-    let mut s = stream.lock().await;
-    let _ = s.write_all(data.as_bytes()).await;
-}
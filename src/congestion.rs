@@ -0,0 +1,74 @@
+//! AIMD congestion control with slow start
+
+use clap::ValueEnum;
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Cc {
+    None,
+    Aimd,
+}
+
+/// AIMD congestion window, in messages
+pub struct Congestion {
+    enabled: bool,
+    cwnd: f64,
+    ssthresh: f64,
+    // no further decrease for losses of seqs below this (one cut per window)
+    recover: u64,
+}
+
+impl Congestion {
+    pub fn new(cc: Cc, initial_window: usize, ssthresh: usize) -> Self {
+        Congestion {
+            enabled: cc == Cc::Aimd,
+            cwnd: initial_window.max(1) as f64,
+            ssthresh: ssthresh.max(2) as f64,
+            recover: 0,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn cwnd(&self) -> f64 {
+        self.cwnd
+    }
+
+    /// How many messages may be in flight, given the --window cap
+    pub fn window(&self, cap: usize) -> usize {
+        if self.enabled {
+            (self.cwnd as usize).clamp(1, cap)
+        } else {
+            cap
+        }
+    }
+
+    /// One more message acked: +1 in slow start, +1/cwnd (one per RTT) after.
+    /// Returns the new cwnd if the usable window changed.
+    pub fn on_ack(&mut self) -> Option<f64> {
+        if !self.enabled {
+            return None;
+        }
+        let before = self.cwnd as usize;
+        if self.cwnd < self.ssthresh {
+            self.cwnd += 1.0;
+        } else {
+            self.cwnd += 1.0 / self.cwnd;
+        }
+        (self.cwnd as usize != before).then_some(self.cwnd)
+    }
+
+    /// Loss of `lost` (by timeout or duplicate acks) detected while `next_seq`
+    /// is the next seq to be sent: cwnd is halved, once per window.
+    /// Returns the new cwnd if the window was cut.
+    pub fn on_loss(&mut self, lost: u64, next_seq: u64) -> Option<f64> {
+        if !self.enabled || lost < self.recover {
+            return None;
+        }
+        self.recover = next_seq;
+        self.ssthresh = (self.cwnd / 2.0).max(2.0);
+        self.cwnd = self.ssthresh;
+        Some(self.cwnd)
+    }
+}
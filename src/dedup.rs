@@ -0,0 +1,101 @@
+//! Which seqs of a session already arrived, in constant memory.
+//!
+//! Everything up to `cum` (the highest seq below which nothing is missing)
+//! is in. Above it, a fixed bitmap of `WINDOW` bits records the seqs that
+//! arrived out of order. A seq further ahead than that slides the window
+//! forward: the oldest seqs still missing fall below `cum` and count as
+//! received from then on. The sender never has more than its window in
+//! flight, so it only gets that far ahead when it gave up on the missing
+//! ones, and a late copy of those is a duplicate either way.
+
+/// How many seqs above `cum` are tracked
+pub const WINDOW: u64 = 1024;
+
+const WORDS: usize = (WINDOW / 64) as usize;
+
+/// Watermark plus bitmap of out of order seqs; seq `s` is bit `s % WINDOW`
+#[derive(Clone, Debug)]
+pub struct SeqWindow {
+    cum: u64,
+    bits: [u64; WORDS],
+}
+
+impl SeqWindow {
+    /// Nothing above `cum` received yet
+    pub fn new(cum: u64) -> Self {
+        SeqWindow {
+            cum,
+            bits: [0; WORDS],
+        }
+    }
+
+    /// Every seq up to here is known
+    pub fn cum(&self) -> u64 {
+        self.cum
+    }
+
+    /// Whether `seq` arrived already, or is too old to tell (which counts the same)
+    pub fn contains(&self, seq: u64) -> bool {
+        seq <= self.cum || (seq - self.cum <= WINDOW && self.bit(seq))
+    }
+
+    /// Record `seq`; true if the window had to slide past missing seqs to fit it
+    pub fn insert(&mut self, seq: u64) -> bool {
+        if seq <= self.cum {
+            return false;
+        }
+        let slid = seq - self.cum > WINDOW;
+        if slid {
+            self.advance(seq - WINDOW);
+        }
+        self.set(seq, true);
+        while self.bit(self.cum + 1) {
+            self.advance(self.cum + 1);
+        }
+        slid
+    }
+
+    /// Treat everything up to `cum` as received
+    pub fn raise(&mut self, cum: u64) {
+        if cum > self.cum {
+            self.advance(cum);
+            while self.bit(self.cum + 1) {
+                self.advance(self.cum + 1);
+            }
+        }
+    }
+
+    /// The 64 seqs right above `cum`, bit i set if `cum + 1 + i` is held
+    pub fn sack(&self) -> u64 {
+        (0..64)
+            .filter(|i| self.bit(self.cum + 1 + i))
+            .fold(0u64, |bits, i| bits | (1 << i))
+    }
+
+    /// Move `cum` up to `to`, clearing the bits it passes so they can be reused
+    fn advance(&mut self, to: u64) {
+        if to - self.cum >= WINDOW {
+            self.bits = [0; WORDS];
+        } else {
+            for seq in self.cum + 1..=to {
+                self.set(seq, false);
+            }
+        }
+        self.cum = to;
+    }
+
+    fn bit(&self, seq: u64) -> bool {
+        let i = seq % WINDOW;
+        self.bits[(i / 64) as usize] & (1 << (i % 64)) != 0
+    }
+
+    fn set(&mut self, seq: u64, on: bool) {
+        let i = seq % WINDOW;
+        let word = &mut self.bits[(i / 64) as usize];
+        if on {
+            *word |= 1 << (i % 64);
+        } else {
+            *word &= !(1 << (i % 64));
+        }
+    }
+}
@@ -0,0 +1,227 @@
+//! File transfer: the sending side cuts a file into chunks, the receiving
+//! side writes them into place and checks the whole-file SHA-256
+
+use crate::packet::{FileChunk, MAX_SEND, Packet};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+/// Reads a file as a sequence of chunks small enough for one datagram
+pub struct FileSource {
+    file: File,
+    pub template: FileChunk,
+    pub chunk_size: usize,
+    done: bool,
+}
+
+impl FileSource {
+    pub async fn open(path: &Path, mtu: usize) -> std::io::Result<Self> {
+        // first pass: whole-file hash, so every chunk can carry it
+        let sha256 = sha256_file(path).await?;
+        let file = File::open(path).await?;
+        let size = file.metadata().await?.len();
+
+        let template = FileChunk {
+            session: 0,
+            epoch: 0,
+            seq: 0,
+            name: path
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            size,
+            sha256,
+            offset: 0,
+            data: Vec::new(),
+        };
+
+        let overhead = bincode::serialized_size(&Packet::Chunk(template.clone())).unwrap() as usize;
+        if mtu <= overhead {
+            return Err(std::io::Error::other(format!(
+                "--mtu must be larger than the {} byte chunk header",
+                overhead
+            )));
+        }
+
+        Ok(FileSource {
+            file,
+            template,
+            chunk_size: (mtu - overhead).min(MAX_SEND - overhead),
+            done: false,
+        })
+    }
+
+    /// The next chunk, or None once the whole file was handed out
+    /// (an empty file still produces one empty chunk)
+    pub async fn next_chunk(&mut self) -> std::io::Result<Option<FileChunk>> {
+        if self.done {
+            return Ok(None);
+        }
+
+        let mut data = vec![0u8; self.chunk_size];
+        let mut filled = 0;
+        while filled < data.len() {
+            let n = self.file.read(&mut data[filled..]).await?;
+            if n == 0 {
+                break;
+            }
+            filled += n;
+        }
+        data.truncate(filled);
+
+        let offset = self.template.offset;
+        self.template.offset += filled as u64;
+        self.done = self.template.offset >= self.template.size;
+
+        Ok(Some(FileChunk {
+            offset,
+            data,
+            ..self.template.clone()
+        }))
+    }
+}
+
+/// A file being reassembled from its chunks
+struct Transfer {
+    file: File,
+    part_path: PathBuf,
+    final_path: PathBuf,
+    size: u64,
+    sha256: [u8; 32],
+    received: u64,
+    // offsets of the chunks written so far, so a resent one is not counted twice
+    written: HashSet<u64>,
+    last_chunk: Instant,
+}
+
+/// The files currently being received, by name and SHA-256. A transfer is
+/// not tied to the session that started it: after a RESET or a failover
+/// the client sends the rest of the file in a new session, and it goes on
+/// where it left off instead of starting over.
+#[derive(Default)]
+pub struct FileSink {
+    transfers: HashMap<(String, [u8; 32]), Transfer>,
+}
+
+impl FileSink {
+    /// Write a chunk into its file under `dir`. Once the file is complete
+    /// it is checked against its SHA-256; returns (path, size, hash matched).
+    pub async fn store(
+        &mut self,
+        dir: &Path,
+        chunk: FileChunk,
+    ) -> std::io::Result<Option<(PathBuf, u64, bool)>> {
+        let key = (chunk.name.clone(), chunk.sha256);
+        if !self.transfers.contains_key(&key) {
+            // never let the client pick a path outside the output dir
+            let name = Path::new(&chunk.name)
+                .file_name()
+                .ok_or_else(|| std::io::Error::other("bad file name"))?;
+            let final_path = dir.join(name);
+            // two different files of the same name must not share a .part
+            let mut part_path = final_path.clone().into_os_string();
+            part_path.push(".");
+            for byte in &chunk.sha256[..4] {
+                part_path.push(format!("{:02x}", byte));
+            }
+            part_path.push(".part");
+            let part_path = PathBuf::from(part_path);
+
+            let file = OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .open(&part_path)
+                .await?;
+            file.set_len(chunk.size).await?;
+            self.transfers.insert(
+                key.clone(),
+                Transfer {
+                    file,
+                    part_path,
+                    final_path,
+                    size: chunk.size,
+                    sha256: chunk.sha256,
+                    received: 0,
+                    written: HashSet::new(),
+                    last_chunk: Instant::now(),
+                },
+            );
+        }
+
+        let transfer = self.transfers.get_mut(&key).unwrap();
+        transfer.last_chunk = Instant::now();
+        transfer.file.seek(SeekFrom::Start(chunk.offset)).await?;
+        transfer.file.write_all(&chunk.data).await?;
+        if transfer.written.insert(chunk.offset) {
+            transfer.received += chunk.data.len() as u64;
+        }
+        if transfer.received < transfer.size {
+            return Ok(None);
+        }
+
+        let mut transfer = self.transfers.remove(&key).unwrap();
+        transfer.file.flush().await?;
+        drop(transfer.file);
+
+        let ok = sha256_file(&transfer.part_path).await? == transfer.sha256;
+        if ok {
+            tokio::fs::rename(&transfer.part_path, &transfer.final_path).await?;
+        }
+        Ok(Some((transfer.final_path, transfer.size, ok)))
+    }
+
+    /// Gives up on the transfers no chunk arrived for in `idle`; returns
+    /// their .part files, which are left as they are
+    pub fn stalled(&mut self, idle: Duration) -> Vec<PathBuf> {
+        let mut stalled = Vec::new();
+        self.transfers.retain(|_, t| {
+            let alive = t.last_chunk.elapsed() < idle;
+            if !alive {
+                stalled.push(t.part_path.clone());
+            }
+            alive
+        });
+        stalled
+    }
+}
+
+pub async fn sha256_file(path: &Path) -> std::io::Result<[u8; 32]> {
+    let mut file = File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut block = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut block).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&block[..n]);
+    }
+    Ok(hasher.finalize().into())
+}
+
+/// Replaces the file at `path` with `contents` so that a crash at any point
+/// leaves either the old file or the new one: the new one is written to
+/// `<path>.tmp` and synced, renamed over the old one, then the directory is
+/// synced so the rename itself is on disk
+pub async fn replace_durably(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+
+    let mut file = File::create(&tmp).await?;
+    file.write_all(contents).await?;
+    file.sync_all().await?;
+    drop(file);
+    tokio::fs::rename(&tmp, path).await?;
+
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir).await?.sync_all().await
+}
@@ -0,0 +1,45 @@
+//! The proxy's impairments: random drops and delays, configured per direction
+
+use rand::Rng;
+use std::time::Duration;
+
+/// Drop / delay settings for one direction of traffic
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Impairment {
+    /// chance (0..1) that a datagram is dropped
+    pub drop: f64,
+    /// chance (0..1) that a datagram is held back before forwarding
+    pub delay: f64,
+    /// delay range in milliseconds
+    pub delay_min: u64,
+    pub delay_max: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Verdict {
+    Drop,
+    Delay(Duration),
+    Forward,
+}
+
+impl Impairment {
+    /// What happens to the next datagram
+    pub fn judge(&self, rng: &mut impl Rng) -> Verdict {
+        if rng.random::<f64>() < self.drop {
+            return Verdict::Drop;
+        }
+
+        if rng.random::<f64>() < self.delay {
+            let min = self.delay_min;
+            let max = self.delay_max.max(min);
+            let delay = if min == max {
+                min
+            } else {
+                rng.random_range(min..=max)
+            };
+            return Verdict::Delay(Duration::from_millis(delay));
+        }
+
+        Verdict::Forward
+    }
+}
@@ -0,0 +1,155 @@
+//! Durable message journal: every message the server receives is appended
+//! and synced to disk before it is acked, so an ack still means something
+//! after a crash. On the next start the journal is replayed to rebuild
+//! each session's dedup state (see `ReceiverMachine::restore`).
+//!
+//! Sessions that end (FIN, expiry, replaced by a new epoch) get a Closed
+//! record, and replaying skips them. After the replay the journal is
+//! rewritten to hold just the live sessions: one Session record each with
+//! its watermark, plus the messages it still needs from above that. So
+//! the journal only grows with what arrived since the last start.
+//!
+//! Each record is framed as its length (u32 LE), the CRC-32 of the body
+//! (u32 LE) and the bincode encoded `JournalRecord`; a record torn by a
+//! crash mid-write fails its CRC and is cut off when the journal is opened.
+
+use crate::file::replace_durably;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+
+/// One entry of the journal
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum JournalRecord {
+    Message(MessageRecord),
+    /// a session as it stood when the journal was last rewritten
+    Session {
+        session: u64,
+        epoch: u64,
+        isn: u64,
+        from: SocketAddr,
+        /// everything up to here had arrived
+        cum: u64,
+        /// everything below here had been handed out (ordered mode)
+        next: u64,
+    },
+    /// the session is over, nothing of it needs restoring
+    Closed {
+        session: u64,
+        from: SocketAddr,
+    },
+}
+
+/// One received message (or fragment of one)
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MessageRecord {
+    pub session: u64,
+    pub epoch: u64,
+    /// where the session's seqs start
+    pub isn: u64,
+    /// the client address the session belongs to
+    pub from: SocketAddr,
+    pub seq: u64,
+    pub frag_index: u32,
+    pub frag_count: u32,
+    /// wall-clock time it arrived, seconds since the Unix epoch
+    pub received_at: f64,
+    pub payload: Vec<u8>,
+}
+
+/// Append-only journal of received messages
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+}
+
+impl Journal {
+    /// Opens (or creates) the journal at `path` for appending, after cutting
+    /// off a torn last record; returns what it already holds
+    pub async fn open(path: &Path) -> std::io::Result<(Self, Vec<JournalRecord>)> {
+        let bytes = match tokio::fs::read(path).await {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        let (records, intact) = parse(&bytes);
+
+        let file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        if intact < bytes.len() {
+            file.set_len(intact as u64).await?;
+        }
+        let journal = Journal {
+            path: path.to_path_buf(),
+            file,
+        };
+        Ok((journal, records))
+    }
+
+    /// Every intact record in the journal at `path`, oldest first; reading
+    /// stops at the first one that is cut short or fails its CRC
+    pub fn read(path: &Path) -> std::io::Result<Vec<JournalRecord>> {
+        match std::fs::read(path) {
+            Ok(bytes) => Ok(parse(&bytes).0),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Appends one record and waits until it is on disk
+    pub async fn append(&mut self, record: &JournalRecord) -> std::io::Result<()> {
+        self.file.write_all(&frame(record)?).await?;
+        self.file.sync_data().await
+    }
+
+    /// Replaces everything in the journal with `records`; a crash halfway
+    /// leaves the old journal in place
+    pub async fn rewrite(&mut self, records: &[JournalRecord]) -> std::io::Result<()> {
+        let mut bytes = Vec::new();
+        for record in records {
+            bytes.extend_from_slice(&frame(record)?);
+        }
+        replace_durably(&self.path, &bytes).await?;
+        self.file = tokio::fs::OpenOptions::new()
+            .append(true)
+            .open(&self.path)
+            .await?;
+        Ok(())
+    }
+}
+
+fn frame(record: &JournalRecord) -> std::io::Result<Vec<u8>> {
+    let body = bincode::serialize(record).map_err(std::io::Error::other)?;
+    let mut frame = Vec::with_capacity(8 + body.len());
+    frame.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    frame.extend_from_slice(&crc32fast::hash(&body).to_le_bytes());
+    frame.extend_from_slice(&body);
+    Ok(frame)
+}
+
+/// The intact records at the start of `bytes`, and how many bytes they take up
+fn parse(bytes: &[u8]) -> (Vec<JournalRecord>, usize) {
+    let mut records = Vec::new();
+    let mut rest = bytes;
+    while rest.len() >= 8 {
+        let len = u32::from_le_bytes(rest[..4].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(rest[4..8].try_into().unwrap());
+        let Some(body) = rest[8..].get(..len) else {
+            break;
+        };
+        if crc32fast::hash(body) != crc {
+            break;
+        }
+        let Ok(record) = bincode::deserialize(body) else {
+            break;
+        };
+        records.push(record);
+        rest = &rest[8 + len..];
+    }
+    (records, bytes.len() - rest.len())
+}
@@ -0,0 +1,44 @@
+//! Reliable messaging over UDP.
+//!
+//! `ReliableSender` opens a session with a server and delivers messages
+//! (and files) to it reliably; `ReliableReceiver` accepts those sessions
+//! and hands back every message exactly once. The client and server
+//! binaries are thin command line wrappers around the two.
+
+/// println! that only prints when `$on` is true
+macro_rules! say {
+    ($on:expr, $($arg:tt)*) => {
+        if $on {
+            println!($($arg)*);
+        }
+    };
+}
+
+/// eprintln! that only prints when `$on` is true
+macro_rules! say_err {
+    ($on:expr, $($arg:tt)*) => {
+        if $on {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+pub mod congestion;
+pub mod dedup;
+pub mod file;
+pub mod impair;
+pub mod journal;
+pub mod log;
+pub mod machine;
+pub mod outbox;
+pub mod pacer;
+pub mod packet;
+pub mod receiver;
+pub mod retry;
+pub mod rtt;
+pub mod sender;
+pub mod sim;
+pub mod stats;
+
+pub use receiver::{Delivery, ReceiverConfig, ReliableReceiver};
+pub use sender::{DeliveryError, DeliveryReceipt, Pending, ReliableSender, Report, SenderConfig};
@@ -0,0 +1,87 @@
+//! Log events, streamed as JSON lines over TCP to the proxy's log port
+
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LogEvent {
+    pub ts: f64,
+    pub component: String,
+    pub event: String,
+    pub seq: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<f64>,
+}
+
+pub fn timestamp() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs_f64()
+}
+
+/// Writes the events of one component to the log stream from a background task,
+/// so logging never holds up the protocol
+pub struct Logger {
+    component: &'static str,
+    tx: Option<mpsc::Sender<LogEvent>>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl Logger {
+    /// Connects to the log port at `addr`; without an address events are dropped
+    pub async fn connect(addr: Option<&str>, component: &'static str) -> std::io::Result<Self> {
+        let Some(addr) = addr else {
+            return Ok(Logger {
+                component,
+                tx: None,
+                task: None,
+            });
+        };
+
+        let mut stream = TcpStream::connect(addr).await?;
+        let (tx, mut rx) = mpsc::channel::<LogEvent>(1000);
+        let task = tokio::spawn(async move {
+            while let Some(ev) = rx.recv().await {
+                let line = serde_json::to_string(&ev).unwrap() + "\n";
+                let _ = stream.write_all(line.as_bytes()).await;
+            }
+        });
+
+        Ok(Logger {
+            component,
+            tx: Some(tx),
+            task: Some(task),
+        })
+    }
+
+    pub async fn log(&self, event: &str, seq: Option<u64>) {
+        self.log_value(event, seq, None).await;
+    }
+
+    pub async fn log_value(&self, event: &str, seq: Option<u64>, value: Option<f64>) {
+        if let Some(tx) = &self.tx {
+            tx.send(LogEvent {
+                ts: timestamp(),
+                component: self.component.to_string(),
+                event: event.to_string(),
+                seq,
+                value,
+            })
+            .await
+            .ok();
+        }
+    }
+
+    /// Waits until every event logged so far is written out
+    pub async fn close(mut self) {
+        drop(self.tx.take());
+        if let Some(task) = self.task.take() {
+            task.await.ok();
+        }
+    }
+}
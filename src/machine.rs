@@ -0,0 +1,19 @@
+//! The protocol as pure state machines: no sockets, no files, no clock.
+//!
+//! Each machine is fed what happened (a datagram arrived, its timer went
+//! off, the user submitted something) together with the current time, and
+//! answers with a queue of actions for its driver to carry out: put this
+//! datagram on the wire, hand this message to the user, log this event.
+//! `poll_timeout` is the one timer the driver has to arm; when it expires
+//! the driver calls `handle_timeout`. Randomness (session ids, jitter)
+//! comes from a seeded rng, so a machine run on a virtual clock is fully
+//! reproducible.
+//!
+//! `ReliableSender` and `ReliableReceiver` are the tokio drivers of
+//! `SenderMachine` and `ReceiverMachine`.
+
+pub mod receiver;
+pub mod sender;
+
+pub use receiver::ReceiverMachine;
+pub use sender::SenderMachine;
@@ -0,0 +1,833 @@
+//! The receiving side as a state machine, see `crate::receiver` for the protocol
+
+use crate::dedup::SeqWindow;
+use crate::journal::{JournalRecord, MessageRecord};
+use crate::packet::{Ack, FileChunk, Message, Packet, decode, encode, valid_fragment};
+use crate::receiver::{Delivery, ReceiverConfig};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// What the receiver's driver has to do
+#[derive(Debug)]
+pub enum Action {
+    /// put a datagram on the wire
+    Send { to: SocketAddr, datagram: Vec<u8> },
+    Log {
+        event: &'static str,
+        seq: Option<u64>,
+        value: Option<f64>,
+    },
+    /// make a newly received message durable; comes before its ack, so the
+    /// driver must not send that ack until this is on disk
+    Journal {
+        from: SocketAddr,
+        /// where the session's seqs start
+        isn: u64,
+        message: Message,
+        received: Instant,
+    },
+    /// a message is complete
+    Deliver(Delivery),
+    /// write a file chunk away; once it is stored `chunk_stored` acks it,
+    /// if storing fails it stays unacked and the client resends it
+    Store {
+        session: u64,
+        from: SocketAddr,
+        chunk: FileChunk,
+    },
+    /// the session is gone; a journal notes that it needs no restoring, and
+    /// file transfers nobody carried on with can be given up
+    Forget { session: u64, from: SocketAddr },
+    /// something worth telling the user
+    Notice(Notice),
+}
+
+/// Human readable progress, printed by a verbose driver
+#[derive(Clone, Debug)]
+pub enum Notice {
+    NewSession {
+        session: u64,
+        from: SocketAddr,
+        epoch: u64,
+        isn: u64,
+    },
+    Replaced {
+        session: u64,
+        from: SocketAddr,
+    },
+    Established {
+        session: u64,
+    },
+    Closed {
+        session: u64,
+        delivered: u64,
+        duplicates: u64,
+    },
+    UnknownSession {
+        session: u64,
+        from: SocketAddr,
+    },
+    Duplicate {
+        seq: u64,
+    },
+    Malformed {
+        seq: u64,
+        from: SocketAddr,
+    },
+    Held {
+        seq: u64,
+        missing: u64,
+    },
+    Skipped {
+        session: u64,
+        missing: u64,
+    },
+    Late {
+        session: u64,
+        seq: u64,
+    },
+    ClientDown {
+        session: u64,
+        silence: Duration,
+    },
+    ClientBack {
+        session: u64,
+    },
+    Expired {
+        session: u64,
+        from: SocketAddr,
+        idle: Duration,
+    },
+    Restored {
+        sessions: usize,
+        messages: usize,
+    },
+}
+
+impl fmt::Display for Notice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Notice::NewSession {
+                session,
+                from,
+                epoch,
+                isn,
+            } => write!(
+                f,
+                "New session {} from {} (epoch {}, isn {})",
+                session, from, epoch, isn
+            ),
+            Notice::Replaced { session, from } => write!(
+                f,
+                "Session {} replaced by a new epoch from {}",
+                session, from
+            ),
+            Notice::Established { session } => write!(f, "Session {} established", session),
+            Notice::Closed {
+                session,
+                delivered,
+                duplicates,
+            } => write!(
+                f,
+                "Session {} closed: {} delivered, {} duplicates",
+                session, delivered, duplicates
+            ),
+            Notice::UnknownSession { session, from } => write!(
+                f,
+                "Data for unknown session {} from {}, reset",
+                session, from
+            ),
+            Notice::Duplicate { seq } => write!(f, "Duplicate seq {} ignored", seq),
+            Notice::Malformed { seq, from } => {
+                write!(f, "Malformed fragment seq {} from {} dropped", seq, from)
+            }
+            Notice::Held { seq, missing } => {
+                write!(f, "Seq {} held, waiting for seq {}", seq, missing)
+            }
+            Notice::Skipped { session, missing } => write!(
+                f,
+                "Session {} gave up waiting for seq {}, delivering past it",
+                session, missing
+            ),
+            Notice::Late { session, seq } => write!(
+                f,
+                "Session {}: seq {} came after it was skipped, dropped to keep the order",
+                session, seq
+            ),
+            Notice::ClientDown { session, silence } => write!(
+                f,
+                "Session {} silent for {} ms, client down",
+                session,
+                silence.as_millis()
+            ),
+            Notice::ClientBack { session } => write!(f, "Session {} client is back", session),
+            Notice::Expired {
+                session,
+                from,
+                idle,
+            } => write!(
+                f,
+                "Session {} from {} idle for {} s, expired",
+                session,
+                from,
+                idle.as_secs()
+            ),
+            Notice::Restored { sessions, messages } => write!(
+                f,
+                "Restored {} sessions from {} journaled messages",
+                sessions, messages
+            ),
+        }
+    }
+}
+
+struct Session {
+    epoch: u64,
+    isn: u64,
+    established: bool,
+    // what arrived so far, in constant memory
+    seen: SeqWindow,
+    delivered: u64,
+    duplicates: u64,
+    // partly reassembled messages, keyed by the seq of their first fragment
+    fragments: HashMap<u64, Vec<Option<Vec<u8>>>>,
+    // complete messages waiting for the gaps below them, keyed by first seq,
+    // with the seq right after their last fragment
+    held: BTreeMap<u64, (u64, Delivery)>,
+    // everything below this was handed out or skipped
+    next: u64,
+    // last chunks of files that failed their SHA-256 check, acked as failed
+    corrupt: HashSet<u64>,
+    last_seen: Instant,
+    alive: bool,
+}
+
+impl Session {
+    fn new(epoch: u64, isn: u64, now: Instant) -> Self {
+        Session {
+            epoch,
+            isn,
+            established: false,
+            seen: SeqWindow::new(isn.saturating_sub(1)),
+            delivered: 0,
+            duplicates: 0,
+            fragments: HashMap::new(),
+            held: BTreeMap::new(),
+            next: isn,
+            corrupt: HashSet::new(),
+            last_seen: now,
+            alive: true,
+        }
+    }
+
+    /// Hold a (non-duplicate, `valid_fragment`) fragment; once every fragment
+    /// of its message is in, returns the whole message and the seq of its first fragment
+    fn reassemble(&mut self, msg: Message) -> Option<(Vec<u8>, u64)> {
+        if msg.frag_count <= 1 {
+            return Some((msg.data, msg.seq));
+        }
+
+        let first = msg.seq - msg.frag_index as u64;
+        let parts = self
+            .fragments
+            .entry(first)
+            .or_insert_with(|| vec![None; msg.frag_count as usize]);
+        let slot = parts.get_mut(msg.frag_index as usize)?;
+        *slot = Some(msg.data);
+
+        if parts.iter().any(|p| p.is_none()) {
+            return None;
+        }
+        let parts = self.fragments.remove(&first)?;
+        Some((parts.into_iter().flatten().flatten().collect(), first))
+    }
+
+    /// Record `seq` as received and move cum past it if the gap below is closed
+    fn accept(&mut self, seq: u64) {
+        self.delivered += 1;
+        if self.seen.insert(seq) {
+            // the window slid past fragments that will never come now
+            let cum = self.seen.cum();
+            self.fragments.retain(|first, parts| {
+                parts
+                    .iter()
+                    .enumerate()
+                    .all(|(i, p)| p.is_some() || first + i as u64 > cum)
+            });
+        }
+    }
+
+    /// The first seq still missing below the oldest held message
+    fn missing(&self) -> Option<u64> {
+        let first = *self.held.keys().next()?;
+        (self.next.max(self.seen.cum() + 1)..first).find(|s| !self.seen.contains(*s))
+    }
+
+    /// Take the held messages nothing is missing below any more, in seq
+    /// order, along with the oldest ones past `limit` whatever is missing;
+    /// each comes with the seq it was handed out without, if any
+    fn release(&mut self, limit: usize) -> Vec<(Option<u64>, Delivery)> {
+        let mut ready = Vec::new();
+        while !self.held.is_empty() {
+            let missing = self.missing();
+            if missing.is_some() && self.held.len() <= limit {
+                break;
+            }
+            let (end, delivery) = self.held.pop_first().unwrap().1;
+            self.next = self.next.max(end);
+            ready.push((missing, delivery));
+        }
+        ready
+    }
+
+    /// The ack for `seq`: cum plus the sack bitmap of what is held above it
+    fn ack(&self, session: u64, seq: u64) -> Packet {
+        Packet::Ack(Ack {
+            session,
+            seq,
+            cum: self.seen.cum(),
+            sack: self.seen.sack(),
+            failed: self.corrupt.contains(&seq),
+        })
+    }
+}
+
+/// A session is only ever spoken to from the address that opened it
+type Key = (SocketAddr, u64);
+
+/// Sessions, dedup, reassembly, acks, client liveness and idle expiry of one receiver
+pub struct ReceiverMachine {
+    heartbeat: Option<Duration>,
+    heartbeat_misses: u32,
+    idle_timeout: Option<Duration>,
+    // hand messages out in seq order
+    ordered: bool,
+    // complete messages a session may hold back waiting for a gap
+    reorder_limit: usize,
+    journal: bool,
+    sessions: HashMap<Key, Session>,
+    next_check: Instant,
+    rng: StdRng,
+    actions: VecDeque<Action>,
+}
+
+impl ReceiverMachine {
+    pub fn new(config: &ReceiverConfig, seed: u64, now: Instant) -> Self {
+        let heartbeat = config.heartbeat_interval.filter(|d| !d.is_zero());
+        ReceiverMachine {
+            heartbeat,
+            heartbeat_misses: config.heartbeat_misses,
+            idle_timeout: config.idle_timeout.filter(|d| !d.is_zero()),
+            ordered: config.ordered,
+            reorder_limit: config.reorder_limit,
+            journal: config.journal.is_some(),
+            sessions: HashMap::new(),
+            next_check: now + heartbeat.unwrap_or_default(),
+            rng: StdRng::seed_from_u64(seed),
+            actions: VecDeque::new(),
+        }
+    }
+
+    /// The next thing the driver has to do
+    pub fn poll_action(&mut self) -> Option<Action> {
+        self.actions.pop_front()
+    }
+
+    /// When `handle_timeout` is due next
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        let liveness = self.heartbeat.map(|_| self.next_check);
+        let expiry = self
+            .idle_timeout
+            .and_then(|idle| self.sessions.values().map(|s| s.last_seen + idle).min());
+        liveness.into_iter().chain(expiry).min()
+    }
+
+    /// Rebuilds the sessions of a previous run from its journal: every
+    /// journaled message counts as received again, so a resend of it is
+    /// acked but not handed out twice. Nothing is delivered or acked here,
+    /// except that what an ordered session was still holding back stays held.
+    /// Returns what the journal has to keep to do the same again: a Session
+    /// record per live session, and the messages above its watermark or of
+    /// a message still missing fragments.
+    pub fn restore(&mut self, now: Instant, records: Vec<JournalRecord>) -> Vec<JournalRecord> {
+        let before = self.actions.len();
+        let mut messages = Vec::new();
+        for record in records {
+            match record {
+                JournalRecord::Session {
+                    session: id,
+                    epoch,
+                    isn,
+                    from,
+                    cum,
+                    next,
+                } => {
+                    let mut session = Session::new(epoch, isn, now);
+                    session.established = true;
+                    session.seen = SeqWindow::new(cum);
+                    session.next = next;
+                    self.sessions.insert((from, id), session);
+                }
+                JournalRecord::Closed { session, from } => {
+                    self.sessions.remove(&(from, session));
+                }
+                JournalRecord::Message(record) => {
+                    let key = self.restored_session(now, &record);
+                    let msg = Message {
+                        session: record.session,
+                        epoch: record.epoch,
+                        data: record.payload.clone(),
+                        seq: record.seq,
+                        frag_index: record.frag_index,
+                        frag_count: record.frag_count,
+                    };
+                    self.take(key, msg);
+                    messages.push(record);
+                }
+            }
+        }
+        // all of that was handed out and acked before the restart
+        self.actions.truncate(before);
+
+        let restored = messages.len();
+        let mut kept: Vec<JournalRecord> = self
+            .sessions
+            .iter()
+            .map(|(&(from, session), s)| JournalRecord::Session {
+                session,
+                epoch: s.epoch,
+                isn: s.isn,
+                from,
+                cum: s.seen.cum(),
+                next: s.next,
+            })
+            .collect();
+        kept.extend(
+            messages
+                .into_iter()
+                .filter(|m| {
+                    self.sessions
+                        .iter()
+                        .find(|((_, id), _)| *id == m.session)
+                        .is_some_and(|(_, s)| {
+                            m.seq > s.seen.cum()
+                                || s.fragments.contains_key(&(m.seq - m.frag_index as u64))
+                        })
+                })
+                .map(JournalRecord::Message),
+        );
+
+        if restored > 0 {
+            self.notice(Notice::Restored {
+                sessions: self.sessions.len(),
+                messages: restored,
+            });
+            self.report_sessions();
+        }
+        kept
+    }
+
+    /// The session a journaled message belongs to, created if it is the first
+    /// one seen, moved if the client had resumed it from a new address
+    fn restored_session(&mut self, now: Instant, record: &MessageRecord) -> Key {
+        let key = (record.from, record.session);
+        let existing = self
+            .sessions
+            .keys()
+            .find(|(_, id)| *id == record.session)
+            .copied();
+        let mut session = match existing {
+            Some(old) => self.sessions.remove(&old).unwrap(),
+            None => {
+                let mut session = Session::new(record.epoch, record.isn, now);
+                session.established = true;
+                session
+            }
+        };
+        // the session was resumed with a later isn, as in open_session
+        if record.isn > session.isn {
+            session.seen.raise(record.isn - 1);
+            session.next = session.next.max(record.isn);
+            session.isn = record.isn;
+        }
+        self.sessions.insert(key, session);
+        key
+    }
+
+    /// Number of sessions currently open
+    pub fn active_sessions(&self) -> usize {
+        self.sessions.len()
+    }
+
+    pub fn handle_timeout(&mut self, now: Instant) {
+        if let Some(interval) = self.heartbeat
+            && now >= self.next_check
+        {
+            self.next_check = now + interval;
+
+            // report clients we have not heard from for too long as down
+            let silence = interval * self.heartbeat_misses.max(1);
+            for ((_, id), session) in self.sessions.iter_mut() {
+                if session.alive && now.saturating_duration_since(session.last_seen) > silence {
+                    session.alive = false;
+                    self.actions.push_back(Action::Notice(Notice::ClientDown {
+                        session: *id,
+                        silence,
+                    }));
+                    // the session id in place of a seq, so the log tells clients apart
+                    self.actions.push_back(Action::Log {
+                        event: "peer_down",
+                        seq: Some(*id),
+                        value: None,
+                    });
+                }
+            }
+        }
+
+        // forget sessions whose client went away without a FIN
+        if let Some(idle) = self.idle_timeout {
+            let expired: Vec<Key> = self
+                .sessions
+                .iter()
+                .filter(|(_, s)| now.saturating_duration_since(s.last_seen) >= idle)
+                .map(|(key, _)| *key)
+                .collect();
+            for key in expired {
+                let mut session = self.sessions.remove(&key).unwrap();
+                self.hand_out(key.1, session.release(0));
+                self.notice(Notice::Expired {
+                    session: key.1,
+                    from: key.0,
+                    idle,
+                });
+                self.log("expire", Some(session.seen.cum()));
+                self.forget(key);
+            }
+        }
+    }
+
+    pub fn handle_datagram(&mut self, now: Instant, from: SocketAddr, datagram: &[u8]) {
+        let Some(packet) = decode(datagram) else {
+            return;
+        };
+
+        let (sid, epoch, seq, body) = match packet {
+            Packet::Syn { epoch, isn } => {
+                let id = self.open_session(now, from, epoch, isn);
+                self.log("syn_recv", Some(isn));
+                let syn_ack = Packet::SynAck {
+                    session: id,
+                    epoch,
+                    isn,
+                };
+                self.send(from, &syn_ack);
+                return;
+            }
+            Packet::HandshakeAck { session } => {
+                let key = (from, session);
+                if self.sessions.contains_key(&key) {
+                    self.seen(now, key);
+                    self.establish(key);
+                }
+                return;
+            }
+            Packet::Heartbeat { session } => {
+                let key = (from, session);
+                let reply = if self.sessions.contains_key(&key) {
+                    self.seen(now, key);
+                    Packet::HeartbeatAck { session }
+                } else {
+                    Packet::Reset { session }
+                };
+                self.send(from, &reply);
+                return;
+            }
+            Packet::Fin { session } => {
+                if let Some(mut s) = self.sessions.remove(&(from, session)) {
+                    self.hand_out(session, s.release(0));
+                    self.notice(Notice::Closed {
+                        session,
+                        delivered: s.delivered,
+                        duplicates: s.duplicates,
+                    });
+                    self.log("close", Some(s.seen.cum()));
+                    self.forget((from, session));
+                }
+                self.send(from, &Packet::FinAck { session });
+                return;
+            }
+            Packet::Data(msg) => (msg.session, msg.epoch, msg.seq, Body::Text(msg)),
+            Packet::Chunk(chunk) => (chunk.session, chunk.epoch, chunk.seq, Body::Chunk(chunk)),
+            _ => return,
+        };
+
+        self.log("recv", Some(seq));
+
+        let key = (from, sid);
+        if self.sessions.get(&key).is_none_or(|s| s.epoch != epoch) {
+            self.notice(Notice::UnknownSession { session: sid, from });
+            self.send(from, &Packet::Reset { session: sid });
+            self.log("reset", Some(seq));
+            return;
+        }
+
+        // never acked, never reassembled: nothing good can come of it
+        if let Body::Text(msg) = &body
+            && !valid_fragment(msg)
+        {
+            self.notice(Notice::Malformed { seq, from });
+            self.log("malformed", Some(seq));
+            return;
+        }
+
+        self.seen(now, key);
+        // data before the handshake ACK means that ACK was lost
+        self.establish(key);
+
+        let session = self.sessions.get_mut(&key).unwrap();
+        if session.seen.contains(seq) {
+            session.duplicates += 1;
+            self.notice(Notice::Duplicate { seq });
+            self.ack(key, seq);
+            return;
+        }
+
+        match body {
+            Body::Text(msg) => {
+                if self.journal {
+                    self.actions.push_back(Action::Journal {
+                        from,
+                        isn: session.isn,
+                        message: msg.clone(),
+                        received: now,
+                    });
+                }
+                self.take(key, msg);
+                self.ack(key, seq);
+            }
+            Body::Chunk(chunk) => {
+                self.actions.push_back(Action::Store {
+                    session: sid,
+                    from,
+                    chunk,
+                });
+            }
+        }
+    }
+
+    /// A new message (or fragment): reassemble it and hand it out, or hold
+    /// it back until the gap below it is filled
+    fn take(&mut self, key: Key, msg: Message) {
+        let (from, sid) = key;
+        let seq = msg.seq;
+        let session = self.sessions.get_mut(&key).unwrap();
+        let count = msg.frag_count.max(1);
+        let whole = session.reassemble(msg);
+        session.accept(seq);
+        if let Some((data, first)) = whole {
+            // its gap was skipped already: handing it out now would break the order
+            if self.ordered && first < session.next {
+                self.notice(Notice::Late {
+                    session: sid,
+                    seq: first,
+                });
+                self.log("late", Some(first));
+                return;
+            }
+            let message = Delivery::Message {
+                session: sid,
+                from,
+                seq: first,
+                fragments: count,
+                data,
+            };
+            let end = first + count as u64;
+            session.held.insert(first, (end, message));
+            self.release(key);
+
+            let session = &self.sessions[&key];
+            if session.held.contains_key(&first)
+                && let Some(missing) = session.missing()
+            {
+                let held = session.held.len();
+                self.notice(Notice::Held {
+                    seq: first,
+                    missing,
+                });
+                self.actions.push_back(Action::Log {
+                    event: "gap",
+                    seq: Some(missing),
+                    value: Some(held as f64),
+                });
+            }
+        }
+    }
+
+    /// A chunk asked for by `Action::Store` is safely written: count it and ack it
+    pub fn chunk_stored(&mut self, session: u64, from: SocketAddr, seq: u64) {
+        let key = (from, session);
+        let Some(s) = self.sessions.get_mut(&key) else {
+            return;
+        };
+        s.accept(seq);
+        // a chunk can fill the gap held messages wait for
+        self.release(key);
+        self.ack(key, seq);
+    }
+
+    /// Chunk `seq` was stored but completed a file that failed its SHA-256
+    /// check: it is acked as failed (again on every resend), so the client
+    /// reports an error instead of a delivered file
+    pub fn file_corrupt(&mut self, session: u64, from: SocketAddr, seq: u64) {
+        if let Some(s) = self.sessions.get_mut(&(from, session)) {
+            s.corrupt.insert(seq);
+        }
+        self.chunk_stored(session, from, seq);
+    }
+
+    /// Hands out what the session no longer has to hold back
+    fn release(&mut self, key: Key) {
+        // unordered, nothing is held back at all
+        let limit = if self.ordered { self.reorder_limit } else { 0 };
+        let ready = self.sessions.get_mut(&key).unwrap().release(limit);
+        self.hand_out(key.1, ready);
+    }
+
+    fn hand_out(&mut self, session: u64, ready: Vec<(Option<u64>, Delivery)>) {
+        for (missing, delivery) in ready {
+            // unordered, nothing waits for gaps in the first place
+            if let Some(missing) = missing
+                && self.ordered
+            {
+                self.notice(Notice::Skipped { session, missing });
+                self.log("skip", Some(missing));
+            }
+            if let Delivery::Message { seq, .. } = delivery {
+                self.log("deliver", Some(seq));
+            }
+            self.actions.push_back(Action::Deliver(delivery));
+        }
+    }
+
+    fn notice(&mut self, notice: Notice) {
+        self.actions.push_back(Action::Notice(notice));
+    }
+
+    fn log(&mut self, event: &'static str, seq: Option<u64>) {
+        self.actions.push_back(Action::Log {
+            event,
+            seq,
+            value: None,
+        });
+    }
+
+    fn send(&mut self, to: SocketAddr, packet: &Packet) {
+        self.actions.push_back(Action::Send {
+            to,
+            datagram: encode(packet),
+        });
+    }
+
+    fn ack(&mut self, key: Key, seq: u64) {
+        let ack = self.sessions[&key].ack(key.1, seq);
+        self.send(key.0, &ack);
+        self.log("ack_send", Some(seq));
+    }
+
+    /// A session is gone: drop its files and report how many are left
+    fn forget(&mut self, key: Key) {
+        self.actions.push_back(Action::Forget {
+            session: key.1,
+            from: key.0,
+        });
+        self.report_sessions();
+    }
+
+    fn report_sessions(&mut self) {
+        self.actions.push_back(Action::Log {
+            event: "sessions",
+            seq: None,
+            value: Some(self.sessions.len() as f64),
+        });
+    }
+
+    fn establish(&mut self, key: Key) {
+        let session = self.sessions.get_mut(&key).unwrap();
+        if !session.established {
+            session.established = true;
+            self.notice(Notice::Established { session: key.1 });
+        }
+    }
+
+    /// Note that a session's client was just heard from, reporting it back up if it was down
+    fn seen(&mut self, now: Instant, key: Key) {
+        let session = self.sessions.get_mut(&key).unwrap();
+        session.last_seen = now;
+        if !session.alive {
+            session.alive = true;
+            self.notice(Notice::ClientBack { session: key.1 });
+            self.log("peer_up", Some(key.1));
+        }
+    }
+
+    /// The session for a SYN: the existing one for a known epoch, otherwise a new one
+    fn open_session(&mut self, now: Instant, addr: SocketAddr, epoch: u64, isn: u64) -> u64 {
+        let existing = self
+            .sessions
+            .iter()
+            .find(|(_, s)| s.epoch == epoch)
+            .map(|(key, _)| *key);
+        if let Some(key) = existing {
+            // a resumed client may come from a new address; the session moves with it
+            let mut session = self.sessions.remove(&key).unwrap();
+            // the client won't send anything below isn again
+            session.seen.raise(isn.saturating_sub(1));
+            session.next = session.next.max(isn);
+            session.isn = session.isn.max(isn);
+            session.last_seen = now;
+            self.sessions.insert((addr, key.1), session);
+            self.release((addr, key.1));
+            return key.1;
+        }
+
+        let stale: Vec<Key> = self
+            .sessions
+            .keys()
+            .filter(|(from, _)| *from == addr)
+            .copied()
+            .collect();
+        for key in stale {
+            let mut session = self.sessions.remove(&key).unwrap();
+            self.hand_out(key.1, session.release(0));
+            self.notice(Notice::Replaced {
+                session: key.1,
+                from: addr,
+            });
+            self.forget(key);
+        }
+
+        let mut id = self.rng.random_range(1..u32::MAX as u64);
+        while self.sessions.keys().any(|(_, s)| *s == id) {
+            id = self.rng.random_range(1..u32::MAX as u64);
+        }
+        self.sessions
+            .insert((addr, id), Session::new(epoch, isn, now));
+        self.notice(Notice::NewSession {
+            session: id,
+            from: addr,
+            epoch,
+            isn,
+        });
+        self.report_sessions();
+        id
+    }
+}
+
+enum Body {
+    Text(Message),
+    Chunk(FileChunk),
+}
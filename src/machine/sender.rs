@@ -0,0 +1,1093 @@
+//! The sending side as a state machine, see `crate::sender` for the protocol
+
+use crate::congestion::Congestion;
+use crate::dedup;
+use crate::outbox::OutboxRecord;
+use crate::packet::{Packet, Payload, decode, encode, encode_data};
+use crate::retry::RetryPolicy;
+use crate::rtt::RttEstimator;
+use crate::sender::{Arq, DeliveryError, DeliveryReceipt, SenderConfig};
+use crate::stats::Stats;
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// What the sender's driver has to do
+#[derive(Debug)]
+pub enum Action {
+    /// point the socket at target `i` of the config; later sends go there
+    Connect(usize),
+    /// put a datagram on the wire and log `event` for `seq`
+    Send {
+        datagram: Vec<u8>,
+        event: &'static str,
+        seq: u64,
+    },
+    /// log an event that is not a datagram
+    Log {
+        event: &'static str,
+        seq: u64,
+        value: Option<f64>,
+    },
+    /// append to the outbox; with `sync` it has to be on disk before the next action
+    Journal { record: OutboxRecord, sync: bool },
+    /// a message ran out of retries (for the dead-letter file)
+    GiveUp {
+        seq: u64,
+        payload: Payload,
+        attempts: u32,
+        first_sent: Instant,
+        last_sent: Instant,
+    },
+    /// the outcome of a submit
+    Receipt {
+        request: u64,
+        result: Result<DeliveryReceipt, DeliveryError>,
+    },
+    /// something worth telling the user
+    Notice(Notice),
+}
+
+/// Human readable progress, printed by a verbose driver
+#[derive(Clone, Debug)]
+pub enum Notice {
+    Established {
+        session: u64,
+        epoch: u64,
+        isn: u64,
+    },
+    Reestablished {
+        session: u64,
+        isn: u64,
+    },
+    FailedOver {
+        session: u64,
+        target: String,
+        isn: u64,
+    },
+    ControlResend {
+        event: &'static str,
+        attempt: u32,
+    },
+    NoAnswer {
+        target: String,
+    },
+    Unreachable {
+        retries: u32,
+    },
+    FailingOver {
+        target: String,
+    },
+    Reset {
+        session: u64,
+    },
+    PeerDown {
+        missed: u32,
+    },
+    PeerUp,
+    Acked {
+        seq: u64,
+    },
+    FastRetransmit {
+        seq: u64,
+    },
+    CwndCut {
+        cwnd: f64,
+    },
+    Resend {
+        seq: u64,
+        attempt: u32,
+        timeout: Duration,
+    },
+    GaveUp {
+        seq: u64,
+        retries: u32,
+        kept: bool,
+    },
+    KeptOpen {
+        count: u64,
+        session: u64,
+    },
+    Corrupt {
+        seq: u64,
+    },
+    NoFinAck,
+}
+
+impl Notice {
+    /// Whether this belongs on stderr
+    pub fn is_warning(&self) -> bool {
+        matches!(
+            self,
+            Notice::NoAnswer { .. }
+                | Notice::Unreachable { .. }
+                | Notice::PeerDown { .. }
+                | Notice::GaveUp { .. }
+                | Notice::Corrupt { .. }
+                | Notice::NoFinAck
+        )
+    }
+}
+
+impl fmt::Display for Notice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Notice::Established {
+                session,
+                epoch,
+                isn,
+            } => write!(
+                f,
+                "Session {} established (epoch {}, isn {})",
+                session, epoch, isn
+            ),
+            Notice::Reestablished { session, isn } => {
+                write!(f, "Session {} established (isn {})", session, isn)
+            }
+            Notice::FailedOver {
+                session,
+                target,
+                isn,
+            } => write!(
+                f,
+                "Session {} established with {} (isn {})",
+                session, target, isn
+            ),
+            Notice::ControlResend { event, attempt } => {
+                write!(f, "Timeout, resend {} (attempt {})", event, attempt)
+            }
+            Notice::NoAnswer { target } => write!(f, "WARNING: no answer from {}", target),
+            Notice::Unreachable { retries } => {
+                write!(f, "ERROR: no answer from server after {} retries", retries)
+            }
+            Notice::FailingOver { target } => {
+                write!(f, "Server {} unreachable, failing over", target)
+            }
+            Notice::Reset { session } => {
+                write!(f, "Session {} reset by server, reconnecting", session)
+            }
+            Notice::PeerDown { missed } => write!(
+                f,
+                "WARNING: server not responding ({} missed heartbeats)",
+                missed
+            ),
+            Notice::PeerUp => write!(f, "Server is responding again"),
+            Notice::Acked { seq } => write!(f, "ACK for seq {}", seq),
+            Notice::FastRetransmit { seq } => {
+                write!(f, "3 duplicate acks, resend seq {}", seq)
+            }
+            Notice::CwndCut { cwnd } => write!(f, "cwnd cut to {:.1}", cwnd),
+            Notice::Resend {
+                seq,
+                attempt,
+                timeout,
+            } => write!(
+                f,
+                "Timeout, resend seq {} (attempt {}, next timeout {} ms)",
+                seq,
+                attempt,
+                timeout.as_millis()
+            ),
+            Notice::GaveUp { seq, retries, kept } => write!(
+                f,
+                "ERROR: seq {} failed after {} retries{}",
+                seq,
+                retries,
+                if *kept { " (kept in outbox)" } else { "" }
+            ),
+            Notice::Corrupt { seq } => write!(
+                f,
+                "ERROR: the file completed by seq {} failed its SHA-256 check on the server",
+                seq
+            ),
+            Notice::KeptOpen { count, session } => write!(
+                f,
+                "{} messages left in outbox, not closing session {}",
+                count, session
+            ),
+            Notice::NoFinAck => write!(f, "WARNING: no FIN-ACK from server, closing anyway"),
+        }
+    }
+}
+
+/// Why a handshake is being made
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Why {
+    Initial,
+    Reset,
+    Failover,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Phase {
+    /// SYN sent to the current target; `tried` counts the targets that already gave up
+    Connecting {
+        isn: u64,
+        tried: usize,
+        why: Why,
+    },
+    Established,
+    /// FIN sent
+    Closing,
+    /// finished; `closed` is false when the session was left open for the outbox
+    Done {
+        closed: bool,
+    },
+    /// no target answered
+    Failed,
+}
+
+/// The SYN or FIN waiting for its answer
+struct Control {
+    datagram: Vec<u8>,
+    event: &'static str,
+    seq: u64,
+    tries: u32,
+    sent_at: Instant,
+    wait: Duration,
+}
+
+/// A payload waiting for room in the window, already carrying its seq
+struct Queued {
+    seq: u64,
+    payload: Payload,
+    message: u64,
+}
+
+struct InFlight {
+    seq: u64,
+    payload: Payload,
+    // the message it is a fragment of, see `receipts`
+    message: u64,
+    encoded: Vec<u8>,
+    tries: u32,
+    acked: bool,
+    sent_at: Instant,
+    deadline: Instant,
+    first_sent: Instant,
+    backoff: Duration,
+}
+
+/// Receipt being put together for one message
+struct Receipt {
+    // the submit to answer (None for messages resumed from the outbox)
+    request: Option<u64>,
+    seq: u64,
+    fragments: u32,
+    remaining: u32,
+    transmissions: u32,
+}
+
+/// Sequencing, the send window, acks, retransmission timers, heartbeats,
+/// failover and teardown of one sender
+pub struct SenderMachine {
+    targets: Vec<String>,
+    arq: Arq,
+    window_size: usize,
+    max_retries: u32,
+    heartbeat: Option<Duration>,
+    heartbeat_misses: u32,
+    // whether the driver keeps an outbox
+    journal: bool,
+    rtt: RttEstimator,
+    retry: RetryPolicy,
+    cc: Congestion,
+    rng: StdRng,
+
+    phase: Phase,
+    control: Option<Control>,
+    epoch: u64,
+    seq: u64,
+    target: usize,
+    session: u64,
+    // in-flight messages, oldest first; the front is always unacked
+    window: VecDeque<InFlight>,
+    backlog: VecDeque<Queued>,
+    // messages not yet acked in full or given up on, by message id
+    receipts: HashMap<u64, Receipt>,
+    next_message: u64,
+    input_open: bool,
+    stats: Stats,
+
+    // duplicate ack detection: acks that don't move cum while the front is still missing
+    last_cum: u64,
+    dup_acks: u32,
+
+    // liveness: heard tells whether anything came from the server since the last heartbeat tick
+    next_heartbeat: Instant,
+    heard: bool,
+    heartbeat_pending: bool,
+    missed: u32,
+    peer_down: bool,
+
+    // switches of target since the last ack
+    failovers: usize,
+
+    actions: VecDeque<Action>,
+}
+
+impl SenderMachine {
+    /// Starts the handshake with the first target. `seq` is the next seq to hand out;
+    /// `resumed` are messages from an earlier run, sent first under their old seqs.
+    pub fn new(
+        config: &SenderConfig,
+        epoch: u64,
+        seq: u64,
+        resumed: impl IntoIterator<Item = (u64, Payload)>,
+        seed: u64,
+        now: Instant,
+    ) -> Self {
+        // put the resumed fragments back together into messages
+        let mut backlog = VecDeque::new();
+        let mut receipts: HashMap<u64, Receipt> = HashMap::new();
+        for (seq, payload) in resumed {
+            let starts = match &payload {
+                Payload::Text(msg) => msg.frag_index == 0,
+                Payload::Chunk(_) => true,
+            };
+            let message = if starts || receipts.is_empty() {
+                receipts.len() as u64
+            } else {
+                receipts.len() as u64 - 1
+            };
+            let r = receipts.entry(message).or_insert(Receipt {
+                request: None,
+                seq,
+                fragments: 0,
+                remaining: 0,
+                transmissions: 0,
+            });
+            r.fragments += 1;
+            r.remaining += 1;
+            backlog.push_back(Queued {
+                seq,
+                payload,
+                message,
+            });
+        }
+        let isn = backlog.front().map_or(seq, |q| q.seq);
+
+        let mut machine = SenderMachine {
+            targets: config.targets.clone(),
+            arq: config.arq,
+            // the receiver can't tell more than its dedup window apart
+            window_size: config.window.clamp(1, dedup::WINDOW as usize),
+            max_retries: config.max_retries,
+            heartbeat: config.heartbeat_interval.filter(|d| !d.is_zero()),
+            heartbeat_misses: config.heartbeat_misses,
+            journal: config.outbox.is_some(),
+            rtt: RttEstimator::new(
+                config.timeout,
+                config.min_rto,
+                config.max_rto.max(config.min_rto),
+            ),
+            retry: RetryPolicy {
+                backoff: config.backoff,
+                jitter: config.jitter,
+                cap: config.backoff_cap,
+                floor: config.min_rto,
+            },
+            cc: Congestion::new(config.cc, config.initial_window, config.ssthresh),
+            rng: StdRng::seed_from_u64(seed),
+            phase: Phase::Failed,
+            control: None,
+            epoch,
+            seq,
+            target: 0,
+            session: 0,
+            window: VecDeque::new(),
+            backlog,
+            next_message: receipts.len() as u64,
+            receipts,
+            input_open: true,
+            stats: Stats::default(),
+            last_cum: 0,
+            dup_acks: 0,
+            next_heartbeat: now,
+            heard: false,
+            heartbeat_pending: false,
+            missed: 0,
+            peer_down: false,
+            failovers: 0,
+            actions: VecDeque::new(),
+        };
+        if !machine.targets.is_empty() {
+            machine.connect(now, 0, isn, Why::Initial, 0);
+        }
+        machine
+    }
+
+    /// The next thing the driver has to do
+    pub fn poll_action(&mut self) -> Option<Action> {
+        self.actions.pop_front()
+    }
+
+    /// When `handle_timeout` is due next
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        match self.phase {
+            Phase::Connecting { .. } | Phase::Closing => {
+                self.control.as_ref().map(|c| c.sent_at + c.wait)
+            }
+            Phase::Established => {
+                let heartbeat = self.heartbeat.map(|_| self.next_heartbeat);
+                self.data_deadline().into_iter().chain(heartbeat).min()
+            }
+            Phase::Done { .. } | Phase::Failed => None,
+        }
+    }
+
+    pub fn is_established(&self) -> bool {
+        self.phase == Phase::Established
+    }
+
+    /// True once no target answered the handshake
+    pub fn is_failed(&self) -> bool {
+        self.phase == Phase::Failed
+    }
+
+    /// Some once the sender is done: true if the session was closed,
+    /// false if it was left open for the messages kept in the outbox
+    pub fn finished(&self) -> Option<bool> {
+        match self.phase {
+            Phase::Done { closed } => Some(closed),
+            _ => None,
+        }
+    }
+
+    /// Whether a submit would go straight into the window
+    pub fn can_accept(&self) -> bool {
+        self.phase == Phase::Established
+            && self.input_open
+            && self.backlog.is_empty()
+            && self.window.len() < self.cc.window(self.window_size)
+    }
+
+    pub fn session(&self) -> u64 {
+        self.session
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    pub fn into_stats(self) -> Stats {
+        self.stats
+    }
+
+    /// Queues the payloads of one message on consecutive seqs; `request`
+    /// is echoed back in the `Receipt` action once they are all acked
+    pub fn submit(&mut self, now: Instant, payloads: Vec<Payload>, request: Option<u64>) {
+        let message = self.next_message;
+        self.next_message += 1;
+        let count = payloads.len() as u32;
+        self.receipts.insert(
+            message,
+            Receipt {
+                request,
+                seq: self.seq,
+                fragments: count,
+                remaining: count,
+                transmissions: 0,
+            },
+        );
+        for payload in payloads {
+            let seq = self.seq;
+            self.seq += 1;
+            if self.journal {
+                let record = OutboxRecord::Enqueue {
+                    seq,
+                    payload: payload.clone(),
+                };
+                self.actions
+                    .push_back(Action::Journal { record, sync: true });
+            }
+            self.backlog.push_back(Queued {
+                seq,
+                payload,
+                message,
+            });
+        }
+        self.fill(now);
+    }
+
+    /// No more submits: once everything is acked or given up, the session is torn down
+    pub fn close(&mut self, now: Instant) {
+        self.input_open = false;
+        self.finish(now);
+    }
+
+    pub fn handle_datagram(&mut self, now: Instant, datagram: &[u8]) {
+        let Some(packet) = decode(datagram) else {
+            return;
+        };
+
+        match (self.phase, packet) {
+            (
+                Phase::Connecting { isn, why, .. },
+                Packet::SynAck {
+                    session,
+                    epoch,
+                    isn: acked_isn,
+                },
+            ) if epoch == self.epoch && acked_isn == isn => {
+                self.answered(now);
+                self.established(now, session, isn, why);
+            }
+            (Phase::Closing, Packet::FinAck { session }) if session == self.session => {
+                self.answered(now);
+                self.log("close", self.seq, None);
+                self.phase = Phase::Done { closed: true };
+            }
+            (Phase::Established, packet) => self.on_packet(now, packet),
+            _ => {}
+        }
+    }
+
+    pub fn handle_timeout(&mut self, now: Instant) {
+        match self.phase {
+            Phase::Connecting { isn, tried, why } => {
+                if self.control_due(now) && !self.resend_control(now) {
+                    if self.targets.len() > 1 {
+                        let target = self.targets[self.target].clone();
+                        self.notice(Notice::NoAnswer { target });
+                    }
+                    if tried + 1 >= self.targets.len() {
+                        self.notice(Notice::Unreachable {
+                            retries: self.max_retries,
+                        });
+                        self.phase = Phase::Failed;
+                        return;
+                    }
+                    self.connect(now, self.target + 1, isn, why, tried + 1);
+                }
+            }
+            Phase::Closing => {
+                if self.control_due(now) && !self.resend_control(now) {
+                    self.notice(Notice::NoFinAck);
+                    self.log("close", self.seq, None);
+                    self.phase = Phase::Done { closed: true };
+                }
+            }
+            Phase::Established => {
+                if self.heartbeat_due(now) {
+                    return;
+                }
+                if self.data_deadline().is_some_and(|t| t <= now) && self.retransmit(now) {
+                    return;
+                }
+                self.fill(now);
+                self.finish(now);
+            }
+            Phase::Done { .. } | Phase::Failed => {}
+        }
+    }
+
+    /// Go-Back-N runs one timer for the oldest message, Selective Repeat one per message
+    fn data_deadline(&self) -> Option<Instant> {
+        match self.arq {
+            Arq::GoBackN => self.window.front().map(|m| m.deadline),
+            Arq::SelectiveRepeat => self
+                .window
+                .iter()
+                .filter(|m| !m.acked)
+                .map(|m| m.deadline)
+                .min(),
+        }
+    }
+
+    fn notice(&mut self, notice: Notice) {
+        self.actions.push_back(Action::Notice(notice));
+    }
+
+    fn log(&mut self, event: &'static str, seq: u64, value: Option<f64>) {
+        self.actions.push_back(Action::Log { event, seq, value });
+    }
+
+    fn send(&mut self, packet: &Packet, event: &'static str, seq: u64) {
+        self.actions.push_back(Action::Send {
+            datagram: encode(packet),
+            event,
+            seq,
+        });
+    }
+
+    /// The oldest seq not yet acked, the isn of a new handshake
+    fn base(&self) -> u64 {
+        self.window
+            .front()
+            .map(|m| m.seq)
+            .or(self.backlog.front().map(|q| q.seq))
+            .unwrap_or(self.seq)
+    }
+
+    /// Send SYN to `target` (wrapping around)
+    fn connect(&mut self, now: Instant, target: usize, isn: u64, why: Why, tried: usize) {
+        self.target = target % self.targets.len();
+        self.phase = Phase::Connecting { isn, tried, why };
+        self.actions.push_back(Action::Connect(self.target));
+        let syn = Packet::Syn {
+            epoch: self.epoch,
+            isn,
+        };
+        self.send_control(now, &syn, "syn_send", isn);
+    }
+
+    fn send_control(&mut self, now: Instant, packet: &Packet, event: &'static str, seq: u64) {
+        let datagram = encode(packet);
+        self.actions.push_back(Action::Send {
+            datagram: datagram.clone(),
+            event,
+            seq,
+        });
+        self.control = Some(Control {
+            datagram,
+            event,
+            seq,
+            tries: 0,
+            sent_at: now,
+            wait: self.rtt.rto(),
+        });
+    }
+
+    fn control_due(&self, now: Instant) -> bool {
+        self.control
+            .as_ref()
+            .is_some_and(|c| c.sent_at + c.wait <= now)
+    }
+
+    /// Resend the control packet under the retry policy; false once it is out of retries
+    fn resend_control(&mut self, now: Instant) -> bool {
+        let Some(c) = self.control.as_mut() else {
+            return false;
+        };
+        if c.tries >= self.max_retries {
+            self.control = None;
+            return false;
+        }
+        c.tries += 1;
+        c.wait = self
+            .retry
+            .delay(self.rtt.rto(), c.tries, c.wait, &mut self.rng);
+        c.sent_at = now;
+        self.actions
+            .push_back(Action::Notice(Notice::ControlResend {
+                event: c.event,
+                attempt: c.tries,
+            }));
+        self.actions.push_back(Action::Send {
+            datagram: c.datagram.clone(),
+            event: c.event,
+            seq: c.seq,
+        });
+        true
+    }
+
+    /// The control packet got its answer; sample the RTT if it was sent once
+    fn answered(&mut self, now: Instant) {
+        if let Some(c) = self.control.take()
+            && c.tries == 0
+        {
+            self.rtt.sample(now.saturating_duration_since(c.sent_at));
+        }
+    }
+
+    fn established(&mut self, now: Instant, session: u64, isn: u64, why: Why) {
+        self.session = session;
+        self.phase = Phase::Established;
+        self.send(&Packet::HandshakeAck { session }, "established", isn);
+
+        match why {
+            Why::Initial => {
+                self.notice(Notice::Established {
+                    session,
+                    epoch: self.epoch,
+                    isn,
+                });
+                if self.cc.enabled() {
+                    self.log("cwnd", self.seq, Some(self.cc.cwnd()));
+                }
+                self.next_heartbeat = now + self.heartbeat.unwrap_or_default();
+            }
+            Why::Reset => {
+                self.notice(Notice::Reestablished { session, isn });
+                self.rearm(now);
+            }
+            Why::Failover => {
+                self.log("failover", isn, Some(self.target as f64));
+                self.notice(Notice::FailedOver {
+                    session,
+                    target: self.targets[self.target].clone(),
+                    isn,
+                });
+                self.rearm(now);
+                for m in self.window.iter_mut() {
+                    m.tries = 0;
+                }
+                self.missed = 0;
+                self.peer_down = false;
+                self.heard = false;
+            }
+        }
+
+        self.fill(now);
+        self.finish(now);
+    }
+
+    /// Re-encode everything in flight for a new session and resend it right away
+    fn rearm(&mut self, now: Instant) {
+        for m in self.window.iter_mut() {
+            m.encoded = encode_data(self.session, self.epoch, m.seq, &m.payload);
+            m.deadline = now;
+        }
+    }
+
+    fn fail_over(&mut self, now: Instant) {
+        self.failovers += 1;
+        let target = self.targets[self.target].clone();
+        self.notice(Notice::FailingOver { target });
+        self.connect(now, self.target + 1, self.base(), Why::Failover, 0);
+    }
+
+    /// Move backlog into the window while there is room
+    fn fill(&mut self, now: Instant) {
+        if self.phase != Phase::Established {
+            return;
+        }
+        while self.window.len() < self.cc.window(self.window_size)
+            && let Some(Queued {
+                seq,
+                payload,
+                message,
+            }) = self.backlog.pop_front()
+        {
+            let encoded = encode_data(self.session, self.epoch, seq, &payload);
+
+            // initial send
+            self.actions.push_back(Action::Send {
+                datagram: encoded.clone(),
+                event: "send",
+                seq,
+            });
+            self.stats.transmissions += 1;
+
+            let rto = self.rtt.rto();
+            self.window.push_back(InFlight {
+                seq,
+                payload,
+                message,
+                encoded,
+                tries: 0,
+                acked: false,
+                sent_at: now,
+                deadline: now + rto,
+                first_sent: now,
+                backoff: rto,
+            });
+        }
+    }
+
+    /// Once closed and everything is acked or given up, send FIN
+    fn finish(&mut self, now: Instant) {
+        if self.phase != Phase::Established
+            || self.input_open
+            || !self.window.is_empty()
+            || !self.backlog.is_empty()
+        {
+            return;
+        }
+
+        // failed messages stay in the outbox; keep the server session for the retry
+        if self.journal && self.stats.failed > 0 {
+            self.notice(Notice::KeptOpen {
+                count: self.stats.failed,
+                session: self.session,
+            });
+            self.phase = Phase::Done { closed: false };
+            return;
+        }
+
+        self.phase = Phase::Closing;
+        let fin = Packet::Fin {
+            session: self.session,
+        };
+        self.send_control(now, &fin, "fin_send", self.seq);
+    }
+
+    fn on_packet(&mut self, now: Instant, packet: Packet) {
+        let from_server = match &packet {
+            Packet::Ack(ack) => ack.session == self.session,
+            Packet::Reset { session: s } | Packet::HeartbeatAck { session: s } => {
+                *s == self.session
+            }
+            _ => false,
+        };
+        if from_server {
+            self.heard = true;
+            self.missed = 0;
+            if self.peer_down {
+                self.peer_down = false;
+                self.notice(Notice::PeerUp);
+                self.log("peer_up", self.seq, None);
+            }
+        }
+
+        let ack = match packet {
+            // ACK received
+            Packet::Ack(ack) if ack.session == self.session => ack,
+
+            // Server forgot us → new session, then resend everything unacked
+            Packet::Reset { session } if session == self.session => {
+                self.notice(Notice::Reset { session });
+                self.connect(now, self.target, self.base(), Why::Reset, 0);
+                return;
+            }
+
+            _ => return,
+        };
+
+        for m in self.window.iter_mut() {
+            let covered = match self.arq {
+                // acks are cumulative, the sack bitmap is for Selective Repeat
+                Arq::GoBackN => m.seq <= ack.cum,
+                Arq::SelectiveRepeat => ack.covers(m.seq),
+            };
+            if m.acked || !covered {
+                continue;
+            }
+            m.acked = true;
+            if m.seq == ack.seq && m.tries == 0 {
+                let sample = now.saturating_duration_since(m.sent_at);
+                self.rtt.sample(sample);
+                self.stats.rtt_samples.push(sample);
+            }
+            self.actions.push_back(Action::Log {
+                event: "ack_recv",
+                seq: m.seq,
+                value: None,
+            });
+            self.failovers = 0;
+            self.actions
+                .push_back(Action::Notice(Notice::Acked { seq: m.seq }));
+            if self.journal {
+                self.actions.push_back(Action::Journal {
+                    record: OutboxRecord::Ack { seq: m.seq },
+                    sync: false,
+                });
+            }
+            self.stats.payloads += 1;
+            if let Some(cwnd) = self.cc.on_ack() {
+                self.actions.push_back(Action::Log {
+                    event: "cwnd",
+                    seq: m.seq,
+                    value: Some(cwnd),
+                });
+            }
+
+            // gone already if another fragment of it was given up on
+            if let Some(r) = self.receipts.get_mut(&m.message) {
+                r.transmissions += m.tries + 1;
+                r.remaining -= 1;
+                if ack.failed && m.seq == ack.seq {
+                    let r = self.receipts.remove(&m.message).unwrap();
+                    self.stats.failed += 1;
+                    self.actions
+                        .push_back(Action::Notice(Notice::Corrupt { seq: m.seq }));
+                    if let Some(request) = r.request {
+                        self.actions.push_back(Action::Receipt {
+                            request,
+                            result: Err(DeliveryError::Corrupt { seq: m.seq }),
+                        });
+                    }
+                } else if r.remaining == 0 {
+                    let r = self.receipts.remove(&m.message).unwrap();
+                    self.stats.delivered += 1;
+                    if let Some(request) = r.request {
+                        self.actions.push_back(Action::Receipt {
+                            request,
+                            result: Ok(DeliveryReceipt {
+                                seq: r.seq,
+                                fragments: r.fragments,
+                                transmissions: r.transmissions,
+                            }),
+                        });
+                    }
+                }
+            }
+        }
+
+        if ack.cum > self.last_cum {
+            self.last_cum = ack.cum;
+            self.dup_acks = 0;
+        } else if self.cc.enabled() && self.window.front().is_some_and(|m| !m.acked) {
+            self.dup_acks += 1;
+            // fast retransmit of the oldest message after 3 duplicate acks
+            if self.dup_acks == 3
+                && let Some(front) = self.window.front_mut()
+            {
+                self.actions.push_back(Action::Send {
+                    datagram: front.encoded.clone(),
+                    event: "send",
+                    seq: front.seq,
+                });
+                self.stats.transmissions += 1;
+                front.tries += 1;
+                front.sent_at = now;
+                let lost = front.seq;
+                self.notice(Notice::FastRetransmit { seq: lost });
+                if let Some(cwnd) = self.cc.on_loss(lost, self.seq) {
+                    self.notice(Notice::CwndCut { cwnd });
+                    self.log("cwnd", lost, Some(cwnd));
+                }
+            }
+        }
+
+        // slide the window past everything confirmed at the front
+        let base = self.window.front().map(|m| m.seq);
+        while self.window.front().is_some_and(|m| m.acked) {
+            self.window.pop_front();
+        }
+        if self.arq == Arq::GoBackN
+            && self.window.front().map(|m| m.seq) != base
+            && let Some(front) = self.window.front_mut()
+        {
+            front.deadline = now + self.rtt.rto();
+        }
+
+        self.fill(now);
+        self.finish(now);
+    }
+
+    /// Heartbeat tick → count a miss if the server stayed silent, then ping it if idle.
+    /// True if the server was declared down and we failed over.
+    fn heartbeat_due(&mut self, now: Instant) -> bool {
+        let Some(interval) = self.heartbeat else {
+            return false;
+        };
+        if now < self.next_heartbeat {
+            return false;
+        }
+
+        let mut fail_over = false;
+        if self.heard {
+            self.missed = 0;
+        } else if self.heartbeat_pending || !self.window.is_empty() {
+            self.missed += 1;
+            if self.missed >= self.heartbeat_misses && !self.peer_down {
+                self.peer_down = true;
+                self.notice(Notice::PeerDown {
+                    missed: self.missed,
+                });
+                self.log("peer_down", self.seq, None);
+                fail_over = self.targets.len() > 1;
+            }
+        }
+        self.heard = false;
+        self.heartbeat_pending = self.window.is_empty();
+        if self.heartbeat_pending {
+            let heartbeat = Packet::Heartbeat {
+                session: self.session,
+            };
+            self.send(&heartbeat, "heartbeat", self.seq);
+        }
+        self.next_heartbeat = now + interval;
+
+        if fail_over {
+            self.fail_over(now);
+        }
+        fail_over
+    }
+
+    /// Timeout → resend (everything from the oldest unacked for Go-Back-N,
+    /// only the expired messages for Selective Repeat).
+    /// True if we ran out of retries and failed over.
+    fn retransmit(&mut self, now: Instant) -> bool {
+        let mut failed = Vec::new();
+        let mut oldest_resent = None;
+        let mut fail_over = false;
+
+        for m in self.window.iter_mut().filter(|m| !m.acked) {
+            if self.arq == Arq::SelectiveRepeat && m.deadline > now {
+                continue;
+            }
+
+            if m.tries >= self.max_retries && self.failovers < self.targets.len() - 1 {
+                fail_over = true;
+                break;
+            }
+
+            if m.tries >= self.max_retries {
+                self.actions.push_back(Action::Notice(Notice::GaveUp {
+                    seq: m.seq,
+                    retries: self.max_retries,
+                    kept: self.journal,
+                }));
+                self.actions.push_back(Action::Log {
+                    event: "give_up",
+                    seq: m.seq,
+                    value: Some((m.tries + 1) as f64),
+                });
+                self.actions.push_back(Action::GiveUp {
+                    seq: m.seq,
+                    payload: m.payload.clone(),
+                    attempts: m.tries + 1,
+                    first_sent: m.first_sent,
+                    last_sent: m.sent_at,
+                });
+                // the whole message fails with its first fragment given up on
+                if let Some(r) = self.receipts.remove(&m.message) {
+                    self.stats.failed += 1;
+                    if let Some(request) = r.request {
+                        self.actions.push_back(Action::Receipt {
+                            request,
+                            result: Err(DeliveryError::Exhausted {
+                                seq: m.seq,
+                                attempts: m.tries + 1,
+                            }),
+                        });
+                    }
+                }
+                failed.push(m.seq);
+                continue;
+            }
+
+            self.actions.push_back(Action::Send {
+                datagram: m.encoded.clone(),
+                event: "send",
+                seq: m.seq,
+            });
+            self.stats.transmissions += 1;
+            oldest_resent = oldest_resent.or(Some(m.seq));
+            m.tries += 1;
+            m.sent_at = now;
+            m.backoff = self
+                .retry
+                .delay(self.rtt.rto(), m.tries, m.backoff, &mut self.rng);
+            m.deadline = now + m.backoff;
+            self.actions.push_back(Action::Notice(Notice::Resend {
+                seq: m.seq,
+                attempt: m.tries,
+                timeout: m.backoff,
+            }));
+        }
+
+        if let Some(lost) = oldest_resent
+            && let Some(cwnd) = self.cc.on_loss(lost, self.seq)
+        {
+            self.notice(Notice::CwndCut { cwnd });
+            self.log("cwnd", lost, Some(cwnd));
+        }
+
+        self.stats.payloads += failed.len() as u64;
+        self.window.retain(|m| !failed.contains(&m.seq));
+        while self.window.front().is_some_and(|m| m.acked) {
+            self.window.pop_front();
+        }
+
+        if fail_over {
+            self.fail_over(now);
+        }
+        fail_over
+    }
+}
@@ -0,0 +1,100 @@
+//! Durable outbox: an append-only journal of messages not yet acked,
+//! replayed on the next start so they are resent under the same epoch and seqs
+
+use crate::file::replace_durably;
+use crate::packet::Payload;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+
+/// One line of the outbox journal
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum OutboxRecord {
+    Open { epoch: u64 },
+    Enqueue { seq: u64, payload: Payload },
+    Ack { seq: u64 },
+}
+
+/// Append-only journal of messages that were handed to us but not yet acked
+pub struct Outbox {
+    file: File,
+    pub epoch: Option<u64>,
+    pub pending: BTreeMap<u64, Payload>,
+    pub next_seq: Option<u64>,
+}
+
+impl Outbox {
+    /// Replays the journal at `path` (if any) and rewrites it compacted
+    /// to just the epoch and the still pending messages
+    pub async fn open(path: &Path) -> std::io::Result<Self> {
+        let mut epoch = None;
+        let mut pending = BTreeMap::new();
+        let mut next_seq = None;
+
+        match tokio::fs::read_to_string(path).await {
+            Ok(text) => {
+                // a torn last line from a crash mid-write is simply skipped
+                for record in text.lines().filter_map(|l| serde_json::from_str(l).ok()) {
+                    match record {
+                        OutboxRecord::Open { epoch: e } => epoch = Some(e),
+                        OutboxRecord::Enqueue { seq, payload } => {
+                            next_seq = Some(next_seq.unwrap_or(0).max(seq + 1));
+                            pending.insert(seq, payload);
+                        }
+                        OutboxRecord::Ack { seq } => {
+                            pending.remove(&seq);
+                        }
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        // only a retry keeps the old identity, a clean outbox starts fresh
+        if pending.is_empty() {
+            epoch = None;
+            next_seq = None;
+        }
+
+        let mut compacted = String::new();
+        if let Some(e) = epoch {
+            compacted += &serde_json::to_string(&OutboxRecord::Open { epoch: e })?;
+            compacted.push('\n');
+        }
+        for (seq, payload) in &pending {
+            let record = OutboxRecord::Enqueue {
+                seq: *seq,
+                payload: payload.clone(),
+            };
+            compacted += &serde_json::to_string(&record)?;
+            compacted.push('\n');
+        }
+        replace_durably(path, compacted.as_bytes()).await?;
+
+        let file = tokio::fs::OpenOptions::new()
+            .append(true)
+            .open(path)
+            .await?;
+        Ok(Outbox {
+            file,
+            epoch,
+            pending,
+            next_seq,
+        })
+    }
+
+    /// Appends one record; `sync` waits until it is on disk
+    pub async fn append(&mut self, record: &OutboxRecord, sync: bool) -> std::io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        self.file.write_all(&line).await?;
+        if sync {
+            self.file.sync_data().await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,168 @@
+//! Wire format shared by the sender and the receiver: one bincode encoded
+//! `Packet` per datagram, up to MAX_SEND bytes (read into MAX_DATAGRAM)
+
+use crate::dedup::WINDOW;
+use serde::{Deserialize, Serialize};
+
+pub const MAX_DATAGRAM: usize = 65535;
+
+/// Largest datagram we send: the most an IPv4 UDP datagram can carry,
+/// anything longer is refused by the kernel
+pub const MAX_SEND: usize = 65507;
+
+/// Most fragments one message may have: all of them have to fit in the
+/// receiver's dedup window at once
+pub const MAX_FRAGMENTS: u32 = WINDOW as u32;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Packet {
+    Syn { epoch: u64, isn: u64 },
+    SynAck { session: u64, epoch: u64, isn: u64 },
+    HandshakeAck { session: u64 },
+    Data(Message),
+    Ack(Ack),
+    Reset { session: u64 },
+    Fin { session: u64 },
+    FinAck { session: u64 },
+    Chunk(FileChunk),
+    Heartbeat { session: u64 },
+    HeartbeatAck { session: u64 },
+}
+
+/// One message (or one fragment of it, see frag_index / frag_count)
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Message {
+    pub session: u64,
+    pub epoch: u64,
+    pub data: Vec<u8>,
+    pub seq: u64,
+    pub frag_index: u32,
+    pub frag_count: u32,
+}
+
+/// A piece of a file, carrying enough about the whole file to reassemble and verify it
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FileChunk {
+    pub session: u64,
+    pub epoch: u64,
+    pub seq: u64,
+    pub name: String,
+    pub size: u64,
+    pub sha256: [u8; 32],
+    pub offset: u64,
+    pub data: Vec<u8>,
+}
+
+/// cum: highest seq received with no gaps below it,
+/// sack: bit i set means seq cum + 1 + i was received too,
+/// failed: seq arrived but completed a file that failed its SHA-256 check
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Ack {
+    pub session: u64,
+    pub seq: u64,
+    #[serde(default)]
+    pub cum: u64,
+    #[serde(default)]
+    pub sack: u64,
+    #[serde(default)]
+    pub failed: bool,
+}
+
+impl Ack {
+    /// Whether this ack confirms `seq`, either directly, cumulatively or via the sack bitmap
+    pub fn covers(&self, seq: u64) -> bool {
+        seq == self.seq
+            || seq <= self.cum
+            || (seq - self.cum - 1 < 64 && self.sack & (1 << (seq - self.cum - 1)) != 0)
+    }
+}
+
+/// What one seq carries: (a fragment of) a message or a piece of a file
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Payload {
+    Text(Message),
+    Chunk(FileChunk),
+}
+
+pub fn encode(packet: &Packet) -> Vec<u8> {
+    bincode::serialize(packet).unwrap()
+}
+
+pub fn decode(datagram: &[u8]) -> Option<Packet> {
+    bincode::deserialize(datagram).ok()
+}
+
+/// The datagram for `payload` sent as `seq` in the given session
+pub fn encode_data(session: u64, epoch: u64, seq: u64, payload: &Payload) -> Vec<u8> {
+    match payload {
+        Payload::Text(msg) => encode(&Packet::Data(Message {
+            session,
+            epoch,
+            seq,
+            ..msg.clone()
+        })),
+        Payload::Chunk(chunk) => encode(&Packet::Chunk(FileChunk {
+            session,
+            epoch,
+            seq,
+            ..chunk.clone()
+        })),
+    }
+}
+
+/// How many bytes of message data fit in one datagram of `mtu` bytes
+/// (at most MAX_SEND)
+pub fn data_budget(mtu: usize) -> std::io::Result<usize> {
+    let empty = Packet::Data(Message {
+        session: 0,
+        epoch: 0,
+        data: Vec::new(),
+        seq: 0,
+        frag_index: 0,
+        frag_count: 0,
+    });
+    let overhead = bincode::serialized_size(&empty).unwrap() as usize;
+
+    if mtu <= overhead {
+        return Err(std::io::Error::other(format!(
+            "--mtu must be larger than {} bytes",
+            overhead
+        )));
+    }
+    Ok((mtu - overhead).min(MAX_SEND - overhead))
+}
+
+/// Whether the fragment fields of `msg` make sense: its index within the
+/// count, no more than MAX_FRAGMENTS, and a first fragment at seq 0 or later
+pub fn valid_fragment(msg: &Message) -> bool {
+    msg.frag_count <= 1
+        || (msg.frag_count <= MAX_FRAGMENTS
+            && msg.frag_index < msg.frag_count
+            && msg.frag_index as u64 <= msg.seq)
+}
+
+/// Split a message into fragments of at most `budget` bytes
+/// (an empty message is still one fragment)
+pub fn fragment(data: &[u8], budget: usize) -> Vec<Payload> {
+    let pieces: Vec<&[u8]> = if data.is_empty() {
+        vec![data]
+    } else {
+        data.chunks(budget.max(1)).collect()
+    };
+
+    let count = pieces.len() as u32;
+    pieces
+        .into_iter()
+        .enumerate()
+        .map(|(i, piece)| {
+            Payload::Text(Message {
+                session: 0,
+                epoch: 0,
+                data: piece.to_vec(),
+                seq: 0,
+                frag_index: i as u32,
+                frag_count: count,
+            })
+        })
+        .collect()
+}
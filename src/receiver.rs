@@ -0,0 +1,325 @@
+//! The receiving side of the protocol.
+//!
+//! Sessions: a client opens a session with SYN { epoch, isn }, we pick a
+//! random session id and answer SYN-ACK { session, epoch, isn }, the client
+//! finishes with an ACK. Every data message and ack carries the session id,
+//! and seq numbers (and duplicate detection) are tracked per session
+//! starting at isn. Sessions belong to the address that opened them, so any
+//! number of clients can be served at once without their seqs colliding;
+//! data for a session we don't know (from that address) is refused with a
+//! RESET. A session we hear nothing from for `idle_timeout` is dropped, and
+//! every change in the number of open sessions is logged as a sessions event.
+//!
+//! Epochs: the epoch is picked at random by each run of a client and is
+//! what the dedup state is scoped to; a SYN for a known epoch gets its
+//! existing session back (lost SYN-ACK, or the client reconnecting), a new
+//! epoch from the same address means the client restarted, so its old
+//! sessions are dropped instead of treating the new run's seqs as
+//! retransmissions.
+//!
+//! Acks carry the seq they answer, cum (the highest seq received with no
+//! gaps below it) and sack, a bitmap of the out-of-order seqs already held
+//! above cum (bit i set means seq cum + 1 + i arrived). Duplicates are
+//! told apart with cum and a fixed window of seqs above it (`SeqWindow`),
+//! so a session's memory stays the same however long it runs; anything
+//! older than the window counts as a duplicate.
+//!
+//! Ordered mode: a message is only handed out once everything below it is
+//! in; one that arrives early is acked right away but held (logged as a gap
+//! event, with the missing seq) until the gap is filled. At most
+//! `reorder_limit` messages are held per session, past that the oldest one
+//! is handed out anyway (skip event), since the client may have given up on
+//! the gap; with a limit of 0 nothing is held and every gap is skipped at
+//! once. A skipped message that turns up after all is dropped (late event):
+//! it was acked, but handing it out would break the order. Every message
+//! handed out is logged as a deliver event.
+//!
+//! Fragments of a long message are held until all of them are in and then
+//! delivered as one message; file chunks are written into the output dir
+//! and the file is delivered once it is complete and its SHA-256 checked.
+//! If the hash does not match, the chunk that completed it is acked as
+//! failed, so the client reports an error instead of a delivered file.
+//! A file is known by its name and SHA-256, not by the session sending it,
+//! so one resumed in a new session (after a RESET or failover) carries on;
+//! one no chunk arrived for in `idle_timeout` is given up (file_stalled).
+//!
+//! Journal: with a journal file set, every newly received message is
+//! appended to it (see `Journal`) and synced to disk before its ack goes
+//! out. If that write fails `recv` returns the error, the message's ack is
+//! dropped and the receiver stops for good: it only hands out what was
+//! already complete, then fails every call. An ack that promised nothing
+//! would be worse than no ack. On the next start the journal is replayed:
+//! the sessions it mentions come back with their dedup state, so a client
+//! resending what was acked just before a crash gets its ack again but the
+//! message is not handed out twice. Sessions that ended before the crash
+//! stay gone, and the journal is rewritten to just what the live sessions
+//! still need, so it does not grow from one run to the next.
+//!
+//! Heartbeats are answered (or refused with a RESET for unknown sessions);
+//! with a heartbeat interval set, a client we have heard nothing from for
+//! `heartbeat_misses` intervals is reported down, and up again once heard from
+//! (peer_down / peer_up events, with the session id as their seq).
+//!
+//! Teardown: FIN { session } frees the session's state and is answered with
+//! FIN-ACK (also for sessions already gone, so a resent FIN still gets its answer).
+
+use crate::file::FileSink;
+use crate::journal::{Journal, JournalRecord, MessageRecord};
+use crate::log::{Logger, timestamp};
+use crate::machine::ReceiverMachine;
+use crate::machine::receiver::Action;
+use crate::packet::MAX_DATAGRAM;
+use rand::Rng;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Instant;
+use tokio::net::UdpSocket;
+use tokio::time::{Duration, sleep_until};
+
+/// Everything that can be tuned about a receiver
+#[derive(Clone, Debug)]
+pub struct ReceiverConfig {
+    /// ip:port to listen on
+    pub listen: String,
+    /// where received files are written
+    pub output_dir: PathBuf,
+    pub heartbeat_interval: Option<Duration>,
+    pub heartbeat_misses: u32,
+    /// sessions not heard from for this long are dropped (None keeps them forever)
+    pub idle_timeout: Option<Duration>,
+    /// hand out messages in seq order, holding back those that arrive early
+    pub ordered: bool,
+    /// how many early messages a session holds at most; past that the
+    /// oldest is handed out without waiting for the gap below it
+    pub reorder_limit: usize,
+    /// append every received message here, synced before it is acked
+    pub journal: Option<PathBuf>,
+    /// ip:port of the log stream
+    pub log_addr: Option<String>,
+    /// print what the protocol is doing to stdout / stderr
+    pub verbose: bool,
+}
+
+impl ReceiverConfig {
+    pub fn new(listen: impl Into<String>) -> Self {
+        ReceiverConfig {
+            listen: listen.into(),
+            output_dir: PathBuf::from("."),
+            heartbeat_interval: None,
+            heartbeat_misses: 3,
+            idle_timeout: Some(Duration::from_secs(300)),
+            ordered: false,
+            reorder_limit: 256,
+            journal: None,
+            log_addr: None,
+            verbose: false,
+        }
+    }
+}
+
+/// Something a client sent that is now complete
+#[derive(Debug)]
+pub enum Delivery {
+    Message {
+        session: u64,
+        from: SocketAddr,
+        /// seq of the (first fragment of the) message
+        seq: u64,
+        fragments: u32,
+        data: Vec<u8>,
+    },
+    File {
+        session: u64,
+        from: SocketAddr,
+        path: PathBuf,
+        size: u64,
+        /// false if the SHA-256 did not match (the file is kept as .<hash>.part)
+        verified: bool,
+    },
+}
+
+/// Accepts sessions from any number of clients and hands back what they send,
+/// exactly once per message. The protocol itself is `ReceiverMachine`; this
+/// runs it on a socket and writes the files.
+pub struct ReliableReceiver {
+    udp: UdpSocket,
+    logger: Logger,
+    config: ReceiverConfig,
+    machine: ReceiverMachine,
+    files: FileSink,
+    journal: Option<Journal>,
+    // a journal write failed, so no ack may go out any more
+    broken: bool,
+    ready: VecDeque<Delivery>,
+    buf: Vec<u8>,
+}
+
+impl ReliableReceiver {
+    pub async fn bind(config: ReceiverConfig) -> std::io::Result<Self> {
+        let udp = UdpSocket::bind(&config.listen).await?;
+        let logger = Logger::connect(config.log_addr.as_deref(), "server").await?;
+        let mut machine = ReceiverMachine::new(&config, rand::rng().random(), Instant::now());
+        let journal = match &config.journal {
+            Some(path) => {
+                let (mut journal, records) = Journal::open(path).await?;
+                let live = machine.restore(Instant::now(), records);
+                journal.rewrite(&live).await?;
+                Some(journal)
+            }
+            None => None,
+        };
+
+        Ok(ReliableReceiver {
+            udp,
+            logger,
+            config,
+            machine,
+            files: FileSink::default(),
+            journal,
+            broken: false,
+            ready: VecDeque::new(),
+            buf: vec![0u8; MAX_DATAGRAM],
+        })
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.udp.local_addr()
+    }
+
+    /// Runs the protocol until the next message or file is complete
+    pub async fn recv(&mut self) -> std::io::Result<Delivery> {
+        if self.broken {
+            return self
+                .ready
+                .pop_front()
+                .ok_or_else(|| std::io::Error::other("the journal could not be written"));
+        }
+        loop {
+            self.perform().await?;
+            if let Some(delivery) = self.ready.pop_front() {
+                return Ok(delivery);
+            }
+
+            let deadline = self.machine.poll_timeout();
+            tokio::select! {
+                r = self.udp.recv_from(&mut self.buf) => {
+                    let (n, addr) = r?;
+                    self.machine.handle_datagram(Instant::now(), addr, &self.buf[..n]);
+                }
+                _ = sleep_until(deadline.unwrap_or_else(Instant::now).into()), if deadline.is_some() => {
+                    self.machine.handle_timeout(Instant::now());
+                }
+            }
+        }
+    }
+
+    /// Carries out everything the machine asked for so far
+    async fn perform(&mut self) -> std::io::Result<()> {
+        let verbose = self.config.verbose;
+        while let Some(action) = self.machine.poll_action() {
+            match action {
+                Action::Send { to, datagram } => {
+                    self.udp.send_to(&datagram, to).await?;
+                }
+                Action::Log { event, seq, value } => self.logger.log_value(event, seq, value).await,
+                Action::Journal {
+                    from,
+                    isn,
+                    message,
+                    received,
+                } => {
+                    let Some(journal) = self.journal.as_mut() else {
+                        continue;
+                    };
+                    let record = JournalRecord::Message(MessageRecord {
+                        session: message.session,
+                        epoch: message.epoch,
+                        isn,
+                        from,
+                        seq: message.seq,
+                        frag_index: message.frag_index,
+                        frag_count: message.frag_count,
+                        received_at: timestamp() - received.elapsed().as_secs_f64(),
+                        payload: message.data,
+                    });
+                    if let Err(e) = journal.append(&record).await {
+                        // the ack for this message is queued right behind it
+                        self.broken = true;
+                        while self.machine.poll_action().is_some() {}
+                        return Err(e);
+                    }
+                }
+                Action::Deliver(delivery) => self.ready.push_back(delivery),
+                Action::Store {
+                    session,
+                    from,
+                    chunk,
+                } => {
+                    let seq = chunk.seq;
+                    // false if it completed a file that failed its SHA-256 check
+                    let intact = match self.files.store(&self.config.output_dir, chunk).await {
+                        Ok(None) => true,
+                        Ok(Some((path, size, verified))) => {
+                            let event = if verified {
+                                "file_done"
+                            } else {
+                                "file_corrupt"
+                            };
+                            self.logger.log(event, Some(seq)).await;
+                            self.ready.push_back(Delivery::File {
+                                session,
+                                from,
+                                path,
+                                size,
+                                verified,
+                            });
+                            verified
+                        }
+                        // not acked, so the client will resend it
+                        Err(e) => {
+                            say_err!(verbose, "ERROR: could not store chunk seq {}: {}", seq, e);
+                            continue;
+                        }
+                    };
+                    if intact {
+                        self.machine.chunk_stored(session, from, seq);
+                    } else {
+                        self.machine.file_corrupt(session, from, seq);
+                    }
+                }
+                Action::Forget { session, from } => {
+                    // a transfer outlives its session (the client may go on in a new
+                    // one), but not the idle timeout
+                    if let Some(idle) = self.config.idle_timeout {
+                        for part in self.files.stalled(idle) {
+                            self.logger.log("file_stalled", None).await;
+                            say_err!(
+                                verbose,
+                                "WARNING: gave up on {}, no chunk for {:?}",
+                                part.display(),
+                                idle
+                            );
+                        }
+                    }
+                    if let Some(journal) = self.journal.as_mut() {
+                        // at worst the session comes back after a restart and expires again
+                        if let Err(e) = journal
+                            .append(&JournalRecord::Closed { session, from })
+                            .await
+                        {
+                            say_err!(
+                                verbose,
+                                "ERROR: could not journal the end of session {}: {}",
+                                session,
+                                e
+                            );
+                        }
+                    }
+                }
+                Action::Notice(notice) => say!(verbose, "{}", notice),
+            }
+        }
+        Ok(())
+    }
+}
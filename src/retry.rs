@@ -0,0 +1,50 @@
+//! How long to wait before each resend
+
+use clap::ValueEnum;
+use rand::Rng;
+use std::time::Duration;
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Backoff {
+    Fixed,
+    Linear,
+    Exponential,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Jitter {
+    None,
+    Full,
+    Decorrelated,
+}
+
+pub struct RetryPolicy {
+    pub backoff: Backoff,
+    pub jitter: Jitter,
+    pub cap: Duration,
+    pub floor: Duration,
+}
+
+impl RetryPolicy {
+    /// How long to wait for an ack after resend number `tries` (1-based);
+    /// `rng` draws the jitter
+    pub fn delay(&self, rto: Duration, tries: u32, prev: Duration, rng: &mut impl Rng) -> Duration {
+        let delay = match self.backoff {
+            Backoff::Fixed => rto,
+            Backoff::Linear => rto.saturating_mul(tries + 1),
+            Backoff::Exponential => rto.saturating_mul(1 << tries.min(16)),
+        }
+        .min(self.cap);
+
+        let delay = match self.jitter {
+            Jitter::None => delay,
+            Jitter::Full => delay.mul_f64(rng.random::<f64>()),
+            Jitter::Decorrelated => {
+                let hi = prev.saturating_mul(3).max(rto);
+                rng.random_range(rto..=hi).min(self.cap)
+            }
+        };
+
+        delay.max(self.floor)
+    }
+}
@@ -0,0 +1,44 @@
+//! Retransmission timeout from measured round trips
+
+use std::time::Duration;
+
+/// Retransmission timeout estimator (RFC 6298)
+pub struct RttEstimator {
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    rto: Duration,
+    min: Duration,
+    max: Duration,
+}
+
+impl RttEstimator {
+    pub fn new(initial: Duration, min: Duration, max: Duration) -> Self {
+        RttEstimator {
+            srtt: None,
+            rttvar: Duration::ZERO,
+            rto: initial.clamp(min, max),
+            min,
+            max,
+        }
+    }
+
+    /// Feed one RTT measurement from a message that was sent exactly once
+    pub fn sample(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                let err = srtt.abs_diff(rtt);
+                self.rttvar = (self.rttvar * 3 + err) / 4;
+                self.srtt = Some((srtt * 7 + rtt) / 8);
+            }
+        }
+        self.rto = (self.srtt.unwrap() + self.rttvar * 4).clamp(self.min, self.max);
+    }
+
+    pub fn rto(&self) -> Duration {
+        self.rto
+    }
+}
@@ -0,0 +1,585 @@
+//! The sending side of the protocol.
+//!
+//! Reliability mechanism:
+//! open a session with the server (SYN → SYN-ACK → ACK), which gives us a
+//! session id and agrees on the initial seq number; every sender picks a
+//! random epoch that goes with the SYN and every message, so the server
+//! never confuses us with an earlier run. Each message gets a seq number
+//! and is retransmitted until it is acked, or given up on after
+//! `max_retries` resends.
+//!
+//! Go-Back-N pipelining: up to `window` messages are in flight at once,
+//! only the cum of an ack counts (its sack bitmap is ignored), one timer
+//! runs for the oldest unacked message and on timeout everything from it
+//! onward is resent (`window` 1 is plain stop-and-wait). Selective Repeat: every message has its own timer and
+//! only expired ones are resent; the sack bitmap in each ack tells us
+//! which out-of-order seqs the server already holds.
+//!
+//! Adaptive retransmission timeout: starts at `timeout`, then tracks the
+//! measured RTT (see `RttEstimator`); retransmitted messages are never
+//! sampled (Karn's rule). Each resend waits according to the `RetryPolicy`.
+//!
+//! Congestion control (`Cc::Aimd`): the messages in flight are also limited
+//! by a congestion window, see `Congestion`; every change of the usable
+//! window is logged as a cwnd event.
+//!
+//! Pacing: every datagram (new messages, retransmissions, control packets)
+//! first takes a token from a token bucket.
+//!
+//! Outbox: every message is journaled before it is sent and resent on the
+//! next start if it was never acked, under the same epoch and seqs; while
+//! messages are pending no FIN is sent so the server keeps the session.
+//!
+//! Dead letters: a message that runs out of retries is appended to the
+//! dead-letter JSONL file and logged as a give_up event.
+//!
+//! Heartbeats: while nothing is in flight a heartbeat is sent every
+//! interval; an interval in which nothing came back from the server (while
+//! a heartbeat or a message was outstanding) is a miss, and after
+//! `heartbeat_misses` of them the server is reported down (peer_down)
+//! until it answers again (peer_up).
+//!
+//! Failover: with several targets, exhausted retries or a peer_down move
+//! us on to the next target, which gets a new handshake (same epoch) and
+//! everything unacked; messages only give up once every target was tried
+//! without a single ack in between. A RESET from the server (it forgot us)
+//! is answered with a new handshake too.
+//!
+//! Teardown: once the sender is closed and every message is acked or given
+//! up, we send FIN until the server answers FIN-ACK.
+//!
+//! All of the above lives in `SenderMachine`; `ReliableSender` runs it on
+//! a socket, with the pacer, the outbox and dead-letter files and the log.
+
+use crate::congestion::Cc;
+use crate::log::{Logger, timestamp};
+use crate::machine::SenderMachine;
+use crate::machine::sender::Action;
+use crate::outbox::{Outbox, OutboxRecord};
+use crate::pacer::TokenBucket;
+use crate::packet::{FileChunk, MAX_DATAGRAM, MAX_FRAGMENTS, Payload, data_budget, fragment};
+use crate::retry::{Backoff, Jitter};
+use crate::stats::Stats;
+use clap::ValueEnum;
+use rand::Rng;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, sleep_until};
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Arq {
+    GoBackN,
+    SelectiveRepeat,
+}
+
+/// Everything that can be tuned about a sender; `new` gives the same
+/// defaults as the client's command line
+#[derive(Clone, Debug)]
+pub struct SenderConfig {
+    /// servers as ip:port, tried in order
+    pub targets: Vec<String>,
+    pub timeout: Duration,
+    pub max_retries: u32,
+    /// messages in flight at once, at most `dedup::WINDOW`
+    pub window: usize,
+    pub arq: Arq,
+    pub min_rto: Duration,
+    pub max_rto: Duration,
+    pub backoff: Backoff,
+    pub backoff_cap: Duration,
+    pub jitter: Jitter,
+    pub cc: Cc,
+    pub initial_window: usize,
+    pub ssthresh: usize,
+    /// datagrams per second
+    pub pace: Option<f64>,
+    pub pace_burst: f64,
+    pub mtu: usize,
+    pub outbox: Option<PathBuf>,
+    pub dead_letter: Option<PathBuf>,
+    pub heartbeat_interval: Option<Duration>,
+    pub heartbeat_misses: u32,
+    /// ip:port of the log stream
+    pub log_addr: Option<String>,
+    /// print what the protocol is doing to stdout / stderr
+    pub verbose: bool,
+}
+
+impl SenderConfig {
+    pub fn new(target: impl Into<String>) -> Self {
+        SenderConfig {
+            targets: vec![target.into()],
+            timeout: Duration::from_secs(1),
+            max_retries: 5,
+            window: 1,
+            arq: Arq::GoBackN,
+            min_rto: Duration::from_millis(50),
+            max_rto: Duration::from_secs(60),
+            backoff: Backoff::Fixed,
+            backoff_cap: Duration::from_secs(60),
+            jitter: Jitter::None,
+            cc: Cc::None,
+            initial_window: 1,
+            ssthresh: 64,
+            pace: None,
+            pace_burst: 1.0,
+            mtu: 1400,
+            outbox: None,
+            dead_letter: None,
+            heartbeat_interval: None,
+            heartbeat_misses: 3,
+            log_addr: None,
+            verbose: false,
+        }
+    }
+}
+
+/// Proof that a message got through
+#[derive(Clone, Debug)]
+pub struct DeliveryReceipt {
+    /// seq of the message (of its first fragment)
+    pub seq: u64,
+    pub fragments: u32,
+    /// datagrams it took, retransmissions included
+    pub transmissions: u32,
+}
+
+#[derive(Debug)]
+pub enum DeliveryError {
+    /// a fragment of the message ran out of retries
+    Exhausted { seq: u64, attempts: u32 },
+    /// the sender stopped (closed, or the server is gone for good)
+    Closed,
+    /// the message would take more than MAX_FRAGMENTS fragments
+    TooLong { fragments: usize },
+    /// the chunk completed a file the server found did not match its SHA-256
+    Corrupt { seq: u64 },
+}
+
+impl std::fmt::Display for DeliveryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeliveryError::Exhausted { seq, attempts } => {
+                write!(f, "seq {} failed after {} attempts", seq, attempts)
+            }
+            DeliveryError::Closed => write!(f, "sender is closed"),
+            DeliveryError::TooLong { fragments } => write!(
+                f,
+                "message needs {} fragments, at most {} are allowed",
+                fragments, MAX_FRAGMENTS
+            ),
+            DeliveryError::Corrupt { seq } => write!(
+                f,
+                "seq {} completed a file that failed its SHA-256 check on the server",
+                seq
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DeliveryError {}
+
+/// A message that was accepted into the send window; resolves once it is acked or given up
+pub struct Pending(oneshot::Receiver<Result<DeliveryReceipt, DeliveryError>>);
+
+impl Future for Pending {
+    type Output = Result<DeliveryReceipt, DeliveryError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.0)
+            .poll(cx)
+            .map(|r| r.unwrap_or(Err(DeliveryError::Closed)))
+    }
+}
+
+/// What a sender did over its lifetime, handed back by `close`
+pub struct Report {
+    pub session: u64,
+    /// false when the session was left open for messages kept in the outbox
+    pub closed: bool,
+    pub stats: Stats,
+}
+
+struct Request {
+    payloads: Vec<Payload>,
+    accepted: oneshot::Sender<()>,
+    receipt: oneshot::Sender<Result<DeliveryReceipt, DeliveryError>>,
+}
+
+/// A reliable message stream to one server (or the first that answers of several).
+/// The protocol runs on a background task; `send` waits for the message to be acked.
+pub struct ReliableSender {
+    requests: mpsc::Sender<Request>,
+    driver: JoinHandle<std::io::Result<Report>>,
+    budget: usize,
+}
+
+impl ReliableSender {
+    /// Opens a session with the first target that answers the handshake
+    pub async fn connect(config: SenderConfig) -> std::io::Result<Self> {
+        if config.targets.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "no target to send to",
+            ));
+        }
+        let budget = data_budget(config.mtu)?;
+
+        let mut targets = Vec::new();
+        for target in &config.targets {
+            let addr = tokio::net::lookup_host(target).await?.next();
+            targets.push(addr.ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("bad target {}", target),
+                )
+            })?);
+        }
+
+        let udp = UdpSocket::bind("0.0.0.0:0").await?;
+        let logger = Logger::connect(config.log_addr.as_deref(), "client").await?;
+        let pacer = config
+            .pace
+            .filter(|rate| *rate > 0.0)
+            .map(|rate| Mutex::new(TokenBucket::new(rate, config.pace_burst.max(1.0))));
+        let link = Link {
+            udp,
+            logger,
+            pacer,
+            verbose: config.verbose,
+        };
+
+        let dead_letter = match &config.dead_letter {
+            Some(path) => Some(
+                tokio::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .await?,
+            ),
+            None => None,
+        };
+        let mut outbox = match &config.outbox {
+            Some(path) => Some(Outbox::open(path).await?),
+            None => None,
+        };
+        let mut resumed = BTreeMap::new();
+
+        let mut epoch: u64 = rand::rng().random_range(1..u32::MAX as u64);
+        let mut seq: u64 = rand::rng().random_range(1..u32::MAX as u64);
+        if let Some(ob) = outbox.as_mut() {
+            match (ob.epoch, ob.next_seq) {
+                (Some(e), Some(next)) => {
+                    epoch = e;
+                    seq = next;
+                    say!(
+                        config.verbose,
+                        "Resuming {} pending messages from outbox (epoch {})",
+                        ob.pending.len(),
+                        epoch
+                    );
+                    resumed = std::mem::take(&mut ob.pending);
+                }
+                _ => ob.append(&OutboxRecord::Open { epoch }, true).await?,
+            }
+        }
+
+        let machine = SenderMachine::new(
+            &config,
+            epoch,
+            seq,
+            resumed,
+            rand::rng().random(),
+            Instant::now(),
+        );
+        let mut driver = Driver {
+            machine,
+            link,
+            targets,
+            outbox,
+            dead_letter,
+            receipts: HashMap::new(),
+            next_request: 0,
+            buf: vec![0u8; MAX_DATAGRAM],
+        };
+
+        // capacity 1 plus the accepted handshake: a request is only taken once there is room
+        let (requests, mut rx) = mpsc::channel(1);
+        loop {
+            driver.perform().await?;
+            if driver.machine.is_failed() {
+                return Err(std::io::ErrorKind::TimedOut.into());
+            }
+            if driver.machine.is_established() {
+                break;
+            }
+            driver.step(&mut rx).await?;
+        }
+        let driver = tokio::spawn(drive(driver, rx));
+
+        Ok(ReliableSender {
+            requests,
+            driver,
+            budget,
+        })
+    }
+
+    /// Sends one message and waits until it is acked (or given up on)
+    pub async fn send(&self, data: impl Into<Vec<u8>>) -> Result<DeliveryReceipt, DeliveryError> {
+        self.submit(data).await?.await
+    }
+
+    /// Hands one message to the sender, returning as soon as it has room for it;
+    /// messages submitted back to back are pipelined up to the window size
+    pub async fn submit(&self, data: impl Into<Vec<u8>>) -> Result<Pending, DeliveryError> {
+        let payloads = fragment(&data.into(), self.budget);
+        if payloads.len() > MAX_FRAGMENTS as usize {
+            return Err(DeliveryError::TooLong {
+                fragments: payloads.len(),
+            });
+        }
+        self.request(payloads).await
+    }
+
+    /// Like `submit`, for a piece of a file (see `FileSource`)
+    pub async fn submit_chunk(&self, chunk: FileChunk) -> Result<Pending, DeliveryError> {
+        self.request(vec![Payload::Chunk(chunk)]).await
+    }
+
+    async fn request(&self, payloads: Vec<Payload>) -> Result<Pending, DeliveryError> {
+        let (accepted, accepted_rx) = oneshot::channel();
+        let (receipt, receipt_rx) = oneshot::channel();
+        self.requests
+            .send(Request {
+                payloads,
+                accepted,
+                receipt,
+            })
+            .await
+            .map_err(|_| DeliveryError::Closed)?;
+        accepted_rx.await.map_err(|_| DeliveryError::Closed)?;
+        Ok(Pending(receipt_rx))
+    }
+
+    /// Waits for everything submitted to be acked or given up on, then ends the session
+    pub async fn close(self) -> std::io::Result<Report> {
+        drop(self.requests);
+        self.driver.await.map_err(std::io::Error::other)?
+    }
+}
+
+/// The socket to the server plus the log stream every datagram is reported to
+struct Link {
+    udp: UdpSocket,
+    logger: Logger,
+    pacer: Option<Mutex<TokenBucket>>,
+    verbose: bool,
+}
+
+impl Link {
+    async fn log(&self, event: &str, seq: u64) {
+        self.logger.log(event, Some(seq)).await;
+    }
+
+    async fn log_value(&self, event: &str, seq: u64, value: Option<f64>) {
+        self.logger.log_value(event, Some(seq), value).await;
+    }
+
+    async fn send(&self, datagram: &[u8], event: &str, seq: u64) -> std::io::Result<()> {
+        if let Some(pacer) = &self.pacer {
+            let wait = pacer.lock().unwrap().reserve();
+            if !wait.is_zero() {
+                tokio::time::sleep(wait).await;
+            }
+        }
+        // an ICMP "port unreachable" from a dead server is just a lost datagram to us;
+        // it is reported by the next send instead of sending, so that send goes again
+        let refused = |r: &std::io::Result<usize>| matches!(r, Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused);
+        let mut sent = self.udp.send(datagram).await;
+        if refused(&sent) {
+            sent = self.udp.send(datagram).await;
+        }
+        if !refused(&sent) {
+            sent?;
+        }
+        self.log(event, seq).await;
+        Ok(())
+    }
+}
+
+/// One line of the dead-letter file: a message we gave up on
+#[derive(Serialize)]
+struct DeadLetter<'a> {
+    seq: u64,
+    /// "text" for a message (or a fragment of one), "chunk" for part of a file
+    kind: &'static str,
+    /// the bytes sent, as text (invalid UTF-8 replaced)
+    payload: Cow<'a, str>,
+    /// for a chunk: the file and where in it the chunk starts
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    offset: Option<u64>,
+    attempts: u32,
+    first_sent: f64,
+    last_sent: f64,
+}
+
+/// Runs a `SenderMachine`: feeds it datagrams, timeouts and requests, and
+/// carries out what it asks for
+struct Driver {
+    machine: SenderMachine,
+    link: Link,
+    targets: Vec<SocketAddr>,
+    outbox: Option<Outbox>,
+    dead_letter: Option<File>,
+    receipts: HashMap<u64, oneshot::Sender<Result<DeliveryReceipt, DeliveryError>>>,
+    next_request: u64,
+    buf: Vec<u8>,
+}
+
+impl Driver {
+    /// Carries out everything the machine asked for so far
+    async fn perform(&mut self) -> std::io::Result<()> {
+        let verbose = self.link.verbose;
+        while let Some(action) = self.machine.poll_action() {
+            match action {
+                Action::Connect(i) => self.link.udp.connect(self.targets[i]).await?,
+                Action::Send {
+                    datagram,
+                    event,
+                    seq,
+                } => self.link.send(&datagram, event, seq).await?,
+                Action::Log { event, seq, value } => self.link.log_value(event, seq, value).await,
+                Action::Journal { record, sync } => {
+                    if let Some(ob) = self.outbox.as_mut() {
+                        ob.append(&record, sync).await?;
+                    }
+                }
+                Action::GiveUp {
+                    seq,
+                    payload,
+                    attempts,
+                    first_sent,
+                    last_sent,
+                } => {
+                    let Some(dl) = self.dead_letter.as_mut() else {
+                        continue;
+                    };
+                    // wall-clock times of the first and latest transmission
+                    let now = Instant::now();
+                    let wall = |t: Instant| timestamp() - now.duration_since(t).as_secs_f64();
+                    let (kind, data, file, offset) = match &payload {
+                        Payload::Text(msg) => ("text", &msg.data, None, None),
+                        Payload::Chunk(chunk) => (
+                            "chunk",
+                            &chunk.data,
+                            Some(chunk.name.as_str()),
+                            Some(chunk.offset),
+                        ),
+                    };
+                    let record = DeadLetter {
+                        seq,
+                        kind,
+                        payload: String::from_utf8_lossy(data),
+                        file,
+                        offset,
+                        attempts,
+                        first_sent: wall(first_sent),
+                        last_sent: wall(last_sent),
+                    };
+                    let mut line = serde_json::to_vec(&record)?;
+                    line.push(b'\n');
+                    dl.write_all(&line).await?;
+                    // a crash right after must not lose the line
+                    dl.flush().await?;
+                }
+                Action::Receipt { request, result } => {
+                    if let Some(tx) = self.receipts.remove(&request) {
+                        tx.send(result).ok();
+                    }
+                }
+                Action::Notice(notice) => {
+                    if notice.is_warning() {
+                        say_err!(verbose, "{}", notice);
+                    } else {
+                        say!(verbose, "{}", notice);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits for the next datagram, timeout or (while the machine has room)
+    /// request and hands it to the machine
+    async fn step(&mut self, requests: &mut mpsc::Receiver<Request>) -> std::io::Result<()> {
+        let deadline = self.machine.poll_timeout();
+
+        tokio::select! {
+            request = requests.recv(), if self.machine.can_accept() => {
+                let Some(request) = request else {
+                    self.machine.close(Instant::now());
+                    return Ok(());
+                };
+                let id = self.next_request;
+                self.next_request += 1;
+                self.receipts.insert(id, request.receipt);
+                self.machine.submit(Instant::now(), request.payloads, Some(id));
+                // journaled before the submit returns
+                self.perform().await?;
+                request.accepted.send(()).ok();
+            }
+
+            recv_result = self.link.udp.recv(&mut self.buf) => {
+                if let Ok(n) = recv_result {
+                    self.machine.handle_datagram(Instant::now(), &self.buf[..n]);
+                }
+            }
+
+            _ = sleep_until(deadline.unwrap_or_else(Instant::now).into()), if deadline.is_some() => {
+                self.machine.handle_timeout(Instant::now());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The protocol loop on the background task, until the session is torn down
+async fn drive(
+    mut driver: Driver,
+    mut requests: mpsc::Receiver<Request>,
+) -> std::io::Result<Report> {
+    let closed = loop {
+        driver.perform().await?;
+        if driver.machine.is_failed() {
+            return Err(std::io::ErrorKind::TimedOut.into());
+        }
+        if let Some(closed) = driver.machine.finished() {
+            break closed;
+        }
+        driver.step(&mut requests).await?;
+    };
+
+    // let the log stream drain before handing back the report
+    driver.link.logger.close().await;
+
+    Ok(Report {
+        session: driver.machine.session(),
+        closed,
+        stats: driver.machine.into_stats(),
+    })
+}
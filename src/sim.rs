@@ -0,0 +1,335 @@
+//! A client, a server and the proxy between them in one process, on a
+//! virtual clock.
+//!
+//! `Simulation` runs a `SenderMachine` and a `ReceiverMachine` and carries
+//! their datagrams through a simulated proxy that drops and delays them
+//! just like `proxy.rs` does (same `Impairment`s), each hop taking `latency`.
+//! Time only moves when nothing else can happen, straight to the next
+//! arrival or timer, so a run of thousands of messages over a bad link
+//! takes milliseconds; everything random comes from `seed`, so the same
+//! config always plays out the same way.
+//!
+//! File chunks count as stored as soon as they arrive; nothing touches the disk.
+
+use crate::impair::{Impairment, Verdict};
+use crate::machine::{ReceiverMachine, SenderMachine, receiver, sender};
+use crate::packet::{Payload, data_budget, fragment};
+use crate::receiver::{Delivery, ReceiverConfig};
+use crate::sender::{DeliveryError, DeliveryReceipt, SenderConfig};
+use crate::stats::Stats;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap};
+use std::time::{Duration, Instant};
+
+/// Everything about one simulated run
+#[derive(Clone, Debug)]
+pub struct SimConfig {
+    pub sender: SenderConfig,
+    pub receiver: ReceiverConfig,
+    /// what the proxy does to datagrams from the client
+    pub client_to_server: Impairment,
+    /// what the proxy does to datagrams from the server
+    pub server_to_client: Impairment,
+    /// one way delay of each hop, on top of any impairment delay
+    pub latency: Duration,
+    pub seed: u64,
+    /// virtual time after which the run is cut off
+    pub time_limit: Duration,
+}
+
+impl SimConfig {
+    /// A clean 1 ms link with the sender and receiver defaults
+    pub fn new(seed: u64) -> Self {
+        SimConfig {
+            sender: SenderConfig::new("server"),
+            receiver: ReceiverConfig::new("server"),
+            client_to_server: Impairment::default(),
+            server_to_client: Impairment::default(),
+            latency: Duration::from_millis(1),
+            seed,
+            time_limit: Duration::from_secs(24 * 3600),
+        }
+    }
+}
+
+/// One event logged by either side, stamped with virtual time
+#[derive(Clone, Debug, PartialEq)]
+pub struct SimEvent {
+    pub at: Duration,
+    pub component: &'static str,
+    pub event: &'static str,
+    pub seq: Option<u64>,
+}
+
+/// How a run ended
+pub struct Outcome {
+    /// what the server handed out, in order
+    pub delivered: Vec<Vec<u8>>,
+    /// one per message, in the order they were submitted (None if it never resolved)
+    pub receipts: Vec<Option<Result<DeliveryReceipt, DeliveryError>>>,
+    pub stats: Stats,
+    /// the sender finished (every message acked or given up, session torn down)
+    pub finished: bool,
+    /// datagrams the proxy dropped
+    pub dropped: u64,
+    pub elapsed: Duration,
+    pub events: Vec<SimEvent>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Side {
+    Client,
+    Server,
+}
+
+/// A datagram on the wire: arrival time, send order, destination
+type InTransit = (Instant, u64, Side, Vec<u8>);
+
+pub struct Simulation {
+    config: SimConfig,
+    start: Instant,
+    now: Instant,
+    client: SenderMachine,
+    server: ReceiverMachine,
+    budget: usize,
+    rng: StdRng,
+    // datagrams on their way, by arrival time (and send order among equals)
+    wire: BinaryHeap<Reverse<InTransit>>,
+    sent: u64,
+    dropped: u64,
+    delivered: Vec<Vec<u8>>,
+    receipts: BTreeMap<u64, Result<DeliveryReceipt, DeliveryError>>,
+    submitted: u64,
+    events: Vec<SimEvent>,
+}
+
+// where the server sees the client's datagrams come from
+const CLIENT: &str = "127.0.0.1:40000";
+
+impl Simulation {
+    pub fn new(config: SimConfig) -> std::io::Result<Self> {
+        let budget = data_budget(config.sender.mtu)?;
+        let start = Instant::now();
+        let mut rng = StdRng::seed_from_u64(config.seed);
+        let epoch = rng.random_range(1..u32::MAX as u64);
+        let isn = rng.random_range(1..u32::MAX as u64);
+        let client = SenderMachine::new(&config.sender, epoch, isn, [], rng.random(), start);
+        let server = ReceiverMachine::new(&config.receiver, rng.random(), start);
+
+        Ok(Simulation {
+            config,
+            start,
+            now: start,
+            client,
+            server,
+            budget,
+            rng,
+            wire: BinaryHeap::new(),
+            sent: 0,
+            dropped: 0,
+            delivered: Vec::new(),
+            receipts: BTreeMap::new(),
+            submitted: 0,
+            events: Vec::new(),
+        })
+    }
+
+    /// Virtual time since the start
+    pub fn elapsed(&self) -> Duration {
+        self.now - self.start
+    }
+
+    /// Changes what the proxy does from now on
+    pub fn set_impairments(&mut self, client_to_server: Impairment, server_to_client: Impairment) {
+        self.config.client_to_server = client_to_server;
+        self.config.server_to_client = server_to_client;
+    }
+
+    /// Whether the client would take a message right now
+    pub fn can_submit(&self) -> bool {
+        self.client.can_accept()
+    }
+
+    /// Hands one message to the client
+    pub fn submit(&mut self, data: &[u8]) {
+        let payloads: Vec<Payload> = fragment(data, self.budget);
+        self.client.submit(self.now, payloads, Some(self.submitted));
+        self.submitted += 1;
+        self.pump();
+    }
+
+    /// No more messages: the client finishes up and closes the session
+    pub fn close(&mut self) {
+        self.client.close(self.now);
+        self.pump();
+    }
+
+    /// The client is done, or gave up on ever reaching the server
+    pub fn is_finished(&self) -> bool {
+        self.client.finished().is_some() || self.client.is_failed()
+    }
+
+    /// Moves time on to the next arrival or timer and handles it;
+    /// false if nothing is left to happen before the time limit
+    pub fn step(&mut self) -> bool {
+        self.pump();
+
+        let next = [
+            self.wire.peek().map(|Reverse((at, ..))| *at),
+            self.client.poll_timeout(),
+            self.server.poll_timeout(),
+        ]
+        .into_iter()
+        .flatten()
+        .min();
+        let Some(next) = next else {
+            return false;
+        };
+        if next - self.start > self.config.time_limit {
+            return false;
+        }
+        self.now = self.now.max(next);
+
+        while let Some(Reverse((at, ..))) = self.wire.peek()
+            && *at <= self.now
+        {
+            let Reverse((_, _, to, datagram)) = self.wire.pop().unwrap();
+            match to {
+                Side::Client => self.client.handle_datagram(self.now, &datagram),
+                Side::Server => {
+                    let from = CLIENT.parse().unwrap();
+                    self.server.handle_datagram(self.now, from, &datagram)
+                }
+            }
+            self.pump();
+        }
+        if self.client.poll_timeout().is_some_and(|t| t <= self.now) {
+            self.client.handle_timeout(self.now);
+        }
+        if self.server.poll_timeout().is_some_and(|t| t <= self.now) {
+            self.server.handle_timeout(self.now);
+        }
+        self.pump();
+        true
+    }
+
+    /// Submits every message as soon as the client has room, closes, and
+    /// runs until the client is finished (or the time limit)
+    pub fn run<T: AsRef<[u8]>>(mut self, messages: impl IntoIterator<Item = T>) -> Outcome {
+        let mut messages = messages.into_iter().peekable();
+        let mut open = true;
+        loop {
+            while messages.peek().is_some() && self.can_submit() {
+                let message = messages.next().unwrap();
+                self.submit(message.as_ref());
+            }
+            if open && messages.peek().is_none() && self.client.is_established() {
+                open = false;
+                self.close();
+            }
+            if self.is_finished() || !self.step() {
+                break;
+            }
+        }
+        self.outcome()
+    }
+
+    /// Where the run stands
+    pub fn outcome(mut self) -> Outcome {
+        let receipts = (0..self.submitted)
+            .map(|id| self.receipts.remove(&id))
+            .collect();
+        Outcome {
+            delivered: self.delivered,
+            receipts,
+            finished: self.client.finished().is_some(),
+            stats: self.client.into_stats(),
+            dropped: self.dropped,
+            elapsed: self.now - self.start,
+            events: self.events,
+        }
+    }
+
+    /// Carries out what both machines asked for
+    fn pump(&mut self) {
+        loop {
+            if let Some(action) = self.client.poll_action() {
+                self.client_action(action);
+            } else if let Some(action) = self.server.poll_action() {
+                self.server_action(action);
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn client_action(&mut self, action: sender::Action) {
+        match action {
+            sender::Action::Send {
+                datagram,
+                event,
+                seq,
+            } => {
+                self.log("client", event, Some(seq));
+                self.transmit(Side::Server, datagram);
+            }
+            sender::Action::Log { event, seq, .. } => self.log("client", event, Some(seq)),
+            sender::Action::Receipt { request, result } => {
+                self.receipts.insert(request, result);
+            }
+            // one server, no disk
+            sender::Action::Connect(_)
+            | sender::Action::Journal { .. }
+            | sender::Action::GiveUp { .. }
+            | sender::Action::Notice(_) => {}
+        }
+    }
+
+    fn server_action(&mut self, action: receiver::Action) {
+        match action {
+            receiver::Action::Send { datagram, .. } => self.transmit(Side::Client, datagram),
+            receiver::Action::Log { event, seq, .. } => self.log("server", event, seq),
+            receiver::Action::Deliver(Delivery::Message { data, .. }) => self.delivered.push(data),
+            receiver::Action::Deliver(Delivery::File { .. }) => {}
+            receiver::Action::Store {
+                session,
+                from,
+                chunk,
+            } => self.server.chunk_stored(session, from, chunk.seq),
+            receiver::Action::Journal { .. }
+            | receiver::Action::Forget { .. }
+            | receiver::Action::Notice(_) => {}
+        }
+    }
+
+    /// The proxy: drop, delay or forward, then the hop to the other side
+    fn transmit(&mut self, to: Side, datagram: Vec<u8>) {
+        let impairment = match to {
+            Side::Server => self.config.client_to_server,
+            Side::Client => self.config.server_to_client,
+        };
+        let delay = match impairment.judge(&mut self.rng) {
+            Verdict::Drop => {
+                self.dropped += 1;
+                self.log("proxy", "drop", None);
+                return;
+            }
+            Verdict::Delay(delay) => delay,
+            Verdict::Forward => Duration::ZERO,
+        };
+        let at = self.now + self.config.latency * 2 + delay;
+        self.wire.push(Reverse((at, self.sent, to, datagram)));
+        self.sent += 1;
+    }
+
+    fn log(&mut self, component: &'static str, event: &'static str, seq: Option<u64>) {
+        self.events.push(SimEvent {
+            at: self.elapsed(),
+            component,
+            event,
+            seq,
+        });
+    }
+}
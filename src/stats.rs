@@ -0,0 +1,72 @@
+//! Delivery counters and the JSON summary built from them
+
+use serde::Serialize;
+use std::time::Duration;
+
+/// Counters for the delivery report / batch summary
+#[derive(Default)]
+pub struct Stats {
+    /// messages (and file chunks) acked in full
+    pub delivered: u64,
+    /// messages (and file chunks) given up on
+    pub failed: u64,
+    /// fragments and chunks acked or given up on: each was sent once
+    /// before any retransmission
+    pub payloads: u64,
+    pub transmissions: u64,
+    pub rtt_samples: Vec<Duration>,
+}
+
+#[derive(Serialize)]
+pub struct Summary {
+    pub delivered: u64,
+    pub failed: u64,
+    pub transmissions: u64,
+    pub retransmissions: u64,
+    pub retransmission_ratio: f64,
+    pub rtt_ms: Option<RttSummary>,
+}
+
+#[derive(Serialize)]
+pub struct RttSummary {
+    pub min: f64,
+    pub avg: f64,
+    pub p50: f64,
+    pub p95: f64,
+    pub p99: f64,
+}
+
+impl Stats {
+    pub fn summary(&self) -> Summary {
+        let retransmissions = self.transmissions.saturating_sub(self.payloads);
+
+        let mut ms: Vec<f64> = self
+            .rtt_samples
+            .iter()
+            .map(|d| d.as_secs_f64() * 1000.0)
+            .collect();
+        ms.sort_by(f64::total_cmp);
+        // nearest-rank percentile
+        let pct =
+            |p: f64| ms[((p / 100.0 * ms.len() as f64).ceil() as usize).clamp(1, ms.len()) - 1];
+
+        Summary {
+            delivered: self.delivered,
+            failed: self.failed,
+            transmissions: self.transmissions,
+            retransmissions,
+            retransmission_ratio: if self.transmissions == 0 {
+                0.0
+            } else {
+                retransmissions as f64 / self.transmissions as f64
+            },
+            rtt_ms: (!ms.is_empty()).then(|| RttSummary {
+                min: ms[0],
+                avg: ms.iter().sum::<f64>() / ms.len() as f64,
+                p50: pct(50.0),
+                p95: pct(95.0),
+                p99: pct(99.0),
+            }),
+        }
+    }
+}
@@ -0,0 +1,54 @@
+//! The AIMD congestion window on its own
+
+use final_project::congestion::{Cc, Congestion};
+
+#[test]
+fn slow_start_grows_by_one_per_ack() {
+    let mut cc = Congestion::new(Cc::Aimd, 1, 8);
+    for cwnd in 2..=8 {
+        assert_eq!(cc.on_ack(), Some(cwnd as f64));
+    }
+    assert_eq!(cc.window(100), 8);
+}
+
+#[test]
+fn past_ssthresh_it_grows_by_one_per_window() {
+    let mut cc = Congestion::new(Cc::Aimd, 4, 4);
+    // about a window of acks buys one more message (each step is 1/cwnd)
+    for _ in 0..4 {
+        assert_eq!(cc.on_ack(), None);
+    }
+    assert_eq!(cc.on_ack().map(|c| c as usize), Some(5));
+    assert_eq!(cc.window(100), 5);
+    assert_eq!(cc.window(3), 3);
+}
+
+#[test]
+fn timeouts_and_dup_acks_halve_the_window() {
+    let mut cc = Congestion::new(Cc::Aimd, 16, 64);
+    assert_eq!(cc.on_loss(10, 30), Some(8.0));
+    assert_eq!(cc.on_loss(30, 50), Some(4.0));
+    // never below 2
+    assert_eq!(cc.on_loss(50, 60), Some(2.0));
+    assert_eq!(cc.on_loss(60, 70), Some(2.0));
+}
+
+#[test]
+fn one_cut_per_window() {
+    let mut cc = Congestion::new(Cc::Aimd, 16, 64);
+    assert_eq!(cc.on_loss(10, 30), Some(8.0));
+    // the rest of that window was sent before the first cut
+    assert_eq!(cc.on_loss(11, 31), None);
+    assert_eq!(cc.on_loss(29, 31), None);
+    assert_eq!(cc.cwnd(), 8.0);
+    assert_eq!(cc.on_loss(30, 40), Some(4.0));
+}
+
+#[test]
+fn disabled_leaves_the_window_alone() {
+    let mut cc = Congestion::new(Cc::None, 1, 8);
+    assert!(!cc.enabled());
+    assert_eq!(cc.on_ack(), None);
+    assert_eq!(cc.on_loss(1, 5), None);
+    assert_eq!(cc.window(32), 32);
+}
@@ -0,0 +1,64 @@
+//! The receiver's constant-memory duplicate detection
+
+use final_project::dedup::{SeqWindow, WINDOW};
+
+#[test]
+fn out_of_order_seqs_are_held_until_the_gap_closes() {
+    let mut seen = SeqWindow::new(9);
+    assert!(!seen.insert(12));
+    assert!(!seen.insert(11));
+    assert_eq!(seen.cum(), 9);
+    assert_eq!(seen.sack(), 0b110);
+    assert!(seen.contains(11) && !seen.contains(10));
+
+    seen.insert(10);
+    assert_eq!(seen.cum(), 12);
+    assert_eq!(seen.sack(), 0);
+    assert!(seen.contains(3));
+}
+
+#[test]
+fn bits_are_reused_once_cum_passes_them() {
+    let mut seen = SeqWindow::new(0);
+    for seq in 1..=10 * WINDOW {
+        assert!(!seen.contains(seq), "{}", seq);
+        seen.insert(seq);
+    }
+    assert_eq!(seen.cum(), 10 * WINDOW);
+    // seq WINDOW + 1 shares a bit with seq 1, but hasn't arrived
+    let mut seen = SeqWindow::new(0);
+    seen.insert(2);
+    assert!(!seen.contains(WINDOW + 2));
+    seen.insert(1);
+    assert!(!seen.contains(WINDOW + 1));
+}
+
+#[test]
+fn a_seq_past_the_window_slides_it() {
+    let mut seen = SeqWindow::new(0);
+    seen.insert(5);
+    assert!(seen.insert(WINDOW + 10));
+    // 1..=9 are missing but now too old: duplicates
+    assert_eq!(seen.cum(), 10);
+    assert!(seen.contains(1));
+    assert!(seen.contains(WINDOW + 10));
+    assert!(!seen.contains(WINDOW + 9));
+
+    // far ahead throws the whole bitmap away
+    assert!(seen.insert(100 * WINDOW));
+    assert_eq!(seen.cum(), 99 * WINDOW);
+    assert!(!seen.contains(99 * WINDOW + 1));
+    assert_eq!(seen.sack(), 0);
+}
+
+#[test]
+fn raise_moves_cum_and_keeps_what_is_above() {
+    let mut seen = SeqWindow::new(0);
+    seen.insert(20);
+    seen.insert(22);
+    seen.raise(19);
+    assert_eq!(seen.cum(), 20);
+    assert_eq!(seen.sack(), 0b10);
+    seen.raise(5);
+    assert_eq!(seen.cum(), 20);
+}
@@ -0,0 +1,35 @@
+//! A library sender failing over from a dead target to a live one, on 127.0.0.1
+
+use final_project::{ReceiverConfig, ReliableReceiver, ReliableSender, SenderConfig};
+use std::time::{Duration, Instant};
+
+#[tokio::test]
+async fn the_first_syn_to_the_next_target_goes_out() {
+    let mut receiver = ReliableReceiver::bind(ReceiverConfig::new("127.0.0.1:0"))
+        .await
+        .unwrap();
+    let live = receiver.local_addr().unwrap();
+    tokio::spawn(async move { while receiver.recv().await.is_ok() {} });
+    // nobody listens here, so the SYN comes back as ICMP port unreachable
+    let dead = std::net::UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+
+    let mut config = SenderConfig::new(dead.to_string());
+    config.targets.push(live.to_string());
+    config.timeout = Duration::from_millis(300);
+    config.min_rto = Duration::from_millis(300);
+    config.max_retries = 0;
+    let started = Instant::now();
+    let sender = ReliableSender::connect(config).await.unwrap();
+
+    // one timeout for the dead target, not a second one for a lost SYN
+    assert!(
+        started.elapsed() < Duration::from_millis(500),
+        "{:?}",
+        started.elapsed()
+    );
+    sender.send("hello").await.unwrap();
+    sender.close().await.unwrap();
+}
@@ -0,0 +1,185 @@
+//! Files reassembled from their chunks on the receiving side, and what the
+//! sender hears back about them
+
+use final_project::file::{FileSink, FileSource};
+use final_project::machine::{ReceiverMachine, SenderMachine, receiver, sender};
+use final_project::packet::{FileChunk, MAX_SEND, Packet, Payload, data_budget, encode, fragment};
+use final_project::{DeliveryError, DeliveryReceipt, ReceiverConfig, SenderConfig};
+use sha2::{Digest, Sha256};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("files-{}-{}", name, std::process::id()));
+    std::fs::remove_dir_all(&dir).ok();
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// `contents` as chunks of 4 bytes, all sent in `session`
+fn chunks(name: &str, contents: &[u8], session: u64) -> Vec<FileChunk> {
+    let sha256 = Sha256::digest(contents).into();
+    contents
+        .chunks(4)
+        .enumerate()
+        .map(|(i, data)| FileChunk {
+            session,
+            epoch: 1,
+            seq: i as u64,
+            name: name.into(),
+            size: contents.len() as u64,
+            sha256,
+            offset: 4 * i as u64,
+            data: data.to_vec(),
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn a_file_resumed_in_a_new_session_carries_on() {
+    let dir = scratch_dir("resume");
+    let mut sink = FileSink::default();
+    let first = chunks("report.txt", b"hello, world", 1);
+    let second = chunks("report.txt", b"hello, world", 2);
+
+    assert!(sink.store(&dir, first[0].clone()).await.unwrap().is_none());
+    assert!(sink.store(&dir, first[1].clone()).await.unwrap().is_none());
+    // the session was reset: the rest comes in a new one, with one chunk
+    // whose ack had been lost sent again
+    assert!(sink.store(&dir, second[1].clone()).await.unwrap().is_none());
+    let done = sink.store(&dir, second[2].clone()).await.unwrap();
+
+    assert_eq!(done, Some((dir.join("report.txt"), 12, true)));
+    assert_eq!(
+        std::fs::read(dir.join("report.txt")).unwrap(),
+        b"hello, world"
+    );
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[tokio::test]
+async fn files_of_the_same_name_do_not_mix() {
+    let dir = scratch_dir("same-name");
+    let mut sink = FileSink::default();
+    let a = chunks("notes.txt", b"aaaabbbb", 1);
+    let b = chunks("notes.txt", b"ccccdddd", 2);
+
+    for chunk in [&a[0], &b[0], &b[1]] {
+        sink.store(&dir, chunk.clone()).await.unwrap();
+    }
+    assert_eq!(std::fs::read(dir.join("notes.txt")).unwrap(), b"ccccdddd");
+    let done = sink.store(&dir, a[1].clone()).await.unwrap();
+    assert!(matches!(done, Some((_, 8, true))));
+    assert_eq!(std::fs::read(dir.join("notes.txt")).unwrap(), b"aaaabbbb");
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[tokio::test]
+async fn stalled_transfers_are_given_up() {
+    let dir = scratch_dir("stalled");
+    let mut sink = FileSink::default();
+    let file = chunks("big.bin", b"0123456789", 1);
+    sink.store(&dir, file[0].clone()).await.unwrap();
+
+    assert!(sink.stalled(Duration::from_secs(60)).is_empty());
+    let stalled = sink.stalled(Duration::ZERO);
+    assert_eq!(stalled.len(), 1);
+    assert!(stalled[0].exists());
+    assert!(sink.stalled(Duration::ZERO).is_empty());
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[tokio::test]
+async fn chunks_never_outgrow_a_udp_datagram() {
+    let dir = scratch_dir("big");
+    let path = dir.join("big.bin");
+    std::fs::write(&path, vec![7u8; 200_000]).unwrap();
+
+    let mut source = FileSource::open(&path, 65535).await.unwrap();
+    let chunk = source.next_chunk().await.unwrap().unwrap();
+    assert_eq!(encode(&Packet::Chunk(chunk)).len(), MAX_SEND);
+
+    let budget = data_budget(65535).unwrap();
+    let text = fragment(&vec![b'x'; 200_000], budget);
+    let Payload::Text(msg) = &text[0] else {
+        panic!("{:?}", text[0]);
+    };
+    assert_eq!(encode(&Packet::Data(msg.clone())).len(), MAX_SEND);
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+/// Runs a sender and a receiver machine against each other, with chunks
+/// stored in `sink`, until neither has anything left to do; the receipts
+/// the sender handed out
+async fn exchange(
+    client: &mut SenderMachine,
+    server: &mut ReceiverMachine,
+    sink: &mut FileSink,
+    dir: &Path,
+) -> Vec<Result<DeliveryReceipt, DeliveryError>> {
+    let now = Instant::now();
+    let addr: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+    let mut receipts = Vec::new();
+    loop {
+        let mut quiet = true;
+        while let Some(action) = client.poll_action() {
+            match action {
+                sender::Action::Send { datagram, .. } => {
+                    server.handle_datagram(now, addr, &datagram)
+                }
+                sender::Action::Receipt { result, .. } => receipts.push(result),
+                _ => {}
+            }
+            quiet = false;
+        }
+        while let Some(action) = server.poll_action() {
+            match action {
+                receiver::Action::Send { datagram, .. } => client.handle_datagram(now, &datagram),
+                receiver::Action::Store {
+                    session,
+                    from,
+                    chunk,
+                } => {
+                    let seq = chunk.seq;
+                    match sink.store(dir, chunk).await.unwrap() {
+                        Some((_, _, false)) => server.file_corrupt(session, from, seq),
+                        _ => server.chunk_stored(session, from, seq),
+                    }
+                }
+                _ => {}
+            }
+            quiet = false;
+        }
+        if quiet {
+            return receipts;
+        }
+    }
+}
+
+#[tokio::test]
+async fn a_file_failing_its_hash_is_not_reported_delivered() {
+    let dir = scratch_dir("corrupt");
+    let now = Instant::now();
+    let mut client = SenderMachine::new(&SenderConfig::new("server"), 7, 100, [], 1, now);
+    let mut server = ReceiverMachine::new(&ReceiverConfig::new("server"), 1, now);
+    let mut sink = FileSink::default();
+    exchange(&mut client, &mut server, &mut sink, &dir).await;
+    assert!(client.is_established());
+
+    let mut chunks = chunks("broken.bin", b"12345678", 0);
+    chunks[1].data = b"xxxx".to_vec();
+    for (request, chunk) in chunks.into_iter().enumerate() {
+        client.submit(now, vec![Payload::Chunk(chunk)], Some(request as u64));
+    }
+    let receipts = exchange(&mut client, &mut server, &mut sink, &dir).await;
+
+    assert!(receipts[0].is_ok());
+    assert!(matches!(
+        receipts[1],
+        Err(DeliveryError::Corrupt { seq: 101 })
+    ));
+    assert_eq!((client.stats().delivered, client.stats().failed), (1, 1));
+    assert!(!dir.join("broken.bin").exists());
+    std::fs::remove_dir_all(&dir).ok();
+}
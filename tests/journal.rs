@@ -0,0 +1,258 @@
+//! The server's message journal, and what a restarted server rebuilds from it
+
+use final_project::journal::{Journal, JournalRecord, MessageRecord};
+use final_project::machine::ReceiverMachine;
+use final_project::machine::receiver::Action;
+use final_project::packet::{Message, Packet, decode, encode};
+use final_project::{Delivery, ReceiverConfig};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+const EPOCH: u64 = 7;
+const ISN: u64 = 100;
+
+fn journal_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("journal-{}-{}.bin", name, std::process::id()));
+    std::fs::remove_file(&path).ok();
+    path
+}
+
+/// A receiver machine driven by hand, its journal kept on disk like the real driver does
+struct Server {
+    machine: ReceiverMachine,
+    journal: Journal,
+    from: SocketAddr,
+    session: u64,
+}
+
+impl Server {
+    async fn start(path: &Path, ordered: bool) -> Server {
+        let mut config = ReceiverConfig::new("server");
+        config.journal = Some(path.to_path_buf());
+        config.ordered = ordered;
+        let mut machine = ReceiverMachine::new(&config, 1, Instant::now());
+        let (mut journal, records) = Journal::open(path).await.unwrap();
+        let live = machine.restore(Instant::now(), records);
+        journal.rewrite(&live).await.unwrap();
+        Server {
+            machine,
+            journal,
+            from: "127.0.0.1:40000".parse().unwrap(),
+            session: 0,
+        }
+    }
+
+    /// Opens the session
+    async fn connect(&mut self) {
+        let syn = Packet::Syn {
+            epoch: EPOCH,
+            isn: ISN,
+        };
+        for action in self.feed(&syn).await {
+            if let Action::Send { datagram, .. } = action
+                && let Some(Packet::SynAck { session, .. }) = decode(&datagram)
+            {
+                self.session = session;
+            }
+        }
+    }
+
+    /// Message `seq` arrives: what was delivered, and the cum of the ack sent back
+    async fn data(&mut self, seq: u64) -> (Vec<String>, Option<u64>) {
+        self.fragment(seq, 0, 1).await
+    }
+
+    /// Fragment `frag_index` of a message arrives, as `data` does
+    async fn fragment(
+        &mut self,
+        seq: u64,
+        frag_index: u32,
+        frag_count: u32,
+    ) -> (Vec<String>, Option<u64>) {
+        let msg = Packet::Data(Message {
+            session: self.session,
+            epoch: EPOCH,
+            data: format!("msg {}", seq).into_bytes(),
+            seq,
+            frag_index,
+            frag_count,
+        });
+        let mut delivered = Vec::new();
+        let mut cum = None;
+        for action in self.feed(&msg).await {
+            match action {
+                Action::Deliver(Delivery::Message { data, .. }) => {
+                    delivered.push(String::from_utf8(data).unwrap())
+                }
+                Action::Send { datagram, .. } => {
+                    if let Some(Packet::Ack(ack)) = decode(&datagram) {
+                        cum = Some(ack.cum);
+                    }
+                }
+                _ => {}
+            }
+        }
+        (delivered, cum)
+    }
+
+    async fn feed(&mut self, packet: &Packet) -> Vec<Action> {
+        self.machine
+            .handle_datagram(Instant::now(), self.from, &encode(packet));
+        let mut actions = Vec::new();
+        while let Some(action) = self.machine.poll_action() {
+            if let Action::Forget { session, from } = action {
+                let record = JournalRecord::Closed { session, from };
+                self.journal.append(&record).await.unwrap();
+            }
+            if let Action::Journal {
+                from, isn, message, ..
+            } = &action
+            {
+                let record = JournalRecord::Message(MessageRecord {
+                    session: message.session,
+                    epoch: message.epoch,
+                    isn: *isn,
+                    from: *from,
+                    seq: message.seq,
+                    frag_index: message.frag_index,
+                    frag_count: message.frag_count,
+                    received_at: 0.0,
+                    payload: message.data.clone(),
+                });
+                self.journal.append(&record).await.unwrap();
+            }
+            actions.push(action);
+        }
+        actions
+    }
+}
+
+#[tokio::test]
+async fn restarted_server_reacks_without_delivering_twice() {
+    let path = journal_path("restart");
+    let mut server = Server::start(&path, false).await;
+    server.connect().await;
+    assert_eq!(server.data(ISN).await.0, ["msg 100"]);
+    assert_eq!(server.data(ISN + 1).await.0, ["msg 101"]);
+
+    // crash: the ack for 101 never made it, so the client resends it
+    let session = server.session;
+    drop(server);
+    let mut server = Server::start(&path, false).await;
+    server.session = session;
+    assert_eq!(server.data(ISN + 1).await, (vec![], Some(ISN + 1)));
+    assert_eq!(
+        server.data(ISN + 2).await,
+        (vec!["msg 102".into()], Some(ISN + 2))
+    );
+    // 100 and 101 were folded into the session's watermark on the restart
+    let records = Journal::read(&path).unwrap();
+    assert_eq!(records.len(), 2);
+    assert!(matches!(records[0], JournalRecord::Session { cum, .. } if cum == ISN + 1));
+    std::fs::remove_file(&path).ok();
+}
+
+#[tokio::test]
+async fn held_messages_stay_held_across_a_restart() {
+    let path = journal_path("held");
+    let mut server = Server::start(&path, true).await;
+    server.connect().await;
+    assert_eq!(server.data(ISN).await.0, ["msg 100"]);
+    assert!(server.data(ISN + 2).await.0.is_empty());
+
+    let session = server.session;
+    drop(server);
+    let mut server = Server::start(&path, true).await;
+    server.session = session;
+    assert_eq!(
+        server.data(ISN + 1).await,
+        (vec!["msg 101".into(), "msg 102".into()], Some(ISN + 2))
+    );
+    std::fs::remove_file(&path).ok();
+}
+
+#[tokio::test]
+async fn a_torn_last_record_is_cut_off() {
+    let path = journal_path("torn");
+    let record = |seq| {
+        JournalRecord::Message(MessageRecord {
+            session: 1,
+            epoch: EPOCH,
+            isn: ISN,
+            from: "127.0.0.1:40000".parse().unwrap(),
+            seq,
+            frag_index: 0,
+            frag_count: 1,
+            received_at: 1.5,
+            payload: b"hello".to_vec(),
+        })
+    };
+    let (mut journal, records) = Journal::open(&path).await.unwrap();
+    assert!(records.is_empty());
+    journal.append(&record(1)).await.unwrap();
+    journal.append(&record(2)).await.unwrap();
+    drop(journal);
+
+    // a crash halfway through writing the third
+    let mut bytes = std::fs::read(&path).unwrap();
+    let whole = bytes.len();
+    bytes.extend_from_slice(&[40, 0, 0, 0, 1, 2, 3]);
+    std::fs::write(&path, bytes).unwrap();
+
+    let (mut journal, records) = Journal::open(&path).await.unwrap();
+    assert_eq!(records, [record(1), record(2)]);
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), whole as u64);
+    journal.append(&record(3)).await.unwrap();
+    assert_eq!(Journal::read(&path).unwrap().len(), 3);
+    std::fs::remove_file(&path).ok();
+}
+
+#[tokio::test]
+async fn closed_sessions_are_not_restored() {
+    let path = journal_path("closed");
+    let mut server = Server::start(&path, false).await;
+    server.connect().await;
+    assert_eq!(server.data(ISN).await.0, ["msg 100"]);
+    let session = server.session;
+    server.feed(&Packet::Fin { session }).await;
+
+    drop(server);
+    let mut server = Server::start(&path, false).await;
+    assert!(Journal::read(&path).unwrap().is_empty());
+    // the session is unknown, so its data is refused
+    server.session = session;
+    let actions = server
+        .feed(&Packet::Data(Message {
+            session,
+            epoch: EPOCH,
+            data: b"msg 101".to_vec(),
+            seq: ISN + 1,
+            frag_index: 0,
+            frag_count: 1,
+        }))
+        .await;
+    assert!(actions.iter().any(|a| matches!(a,
+        Action::Send { datagram, .. } if matches!(decode(datagram), Some(Packet::Reset { .. })))));
+    std::fs::remove_file(&path).ok();
+}
+
+#[tokio::test]
+async fn fragments_of_an_unfinished_message_survive_compaction() {
+    let path = journal_path("fragments");
+    let mut server = Server::start(&path, false).await;
+    server.connect().await;
+    assert_eq!(server.fragment(ISN, 0, 2).await, (vec![], Some(ISN)));
+
+    // restarted twice, so the fragment is carried over by a rewrite
+    let session = server.session;
+    drop(server);
+    drop(Server::start(&path, false).await);
+    let mut server = Server::start(&path, false).await;
+    server.session = session;
+    assert_eq!(
+        server.fragment(ISN + 1, 1, 2).await,
+        (vec!["msg 100msg 101".into()], Some(ISN + 1))
+    );
+    std::fs::remove_file(&path).ok();
+}
@@ -0,0 +1,486 @@
+//! The real client, proxy and server binaries on 127.0.0.1, checked through
+//! their output and the proxy's log file
+
+use final_project::journal::{Journal, JournalRecord, MessageRecord};
+use final_project::log::LogEvent;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader};
+use std::net::{TcpListener, TcpStream, UdpSocket};
+use std::path::PathBuf;
+use std::process::{Child, Command, Output, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const MESSAGES: usize = 40;
+
+/// Kills the process when the test is done with it, pass or fail
+struct Guard(Child);
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        self.0.kill().ok();
+        self.0.wait().ok();
+    }
+}
+
+fn udp_port() -> u16 {
+    UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+fn tcp_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("loopback-{}-{}", name, std::process::id()));
+    std::fs::remove_dir_all(&dir).ok();
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn wait_for_tcp(port: u16) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while TcpStream::connect(("127.0.0.1", port)).is_err() {
+        assert!(Instant::now() < deadline, "log port {} never opened", port);
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+/// Everything one client run left behind
+struct Run {
+    client: Output,
+    summary: serde_json::Value,
+    /// the messages the server printed, in order
+    received: Vec<String>,
+    events: Vec<LogEvent>,
+}
+
+impl Run {
+    fn count(&self, component: &str, event: &str) -> usize {
+        self.events
+            .iter()
+            .filter(|e| e.component == component && e.event == event)
+            .count()
+    }
+}
+
+/// A headless proxy on `port` in front of `target`, dropping `loss` of the
+/// datagrams in each direction
+struct Proxy {
+    port: u16,
+    target: u16,
+    loss: f64,
+    seed: u64,
+    log_port: u16,
+    log_file: PathBuf,
+}
+
+impl Proxy {
+    fn spawn(&self, proxy_args: &[&str]) -> Guard {
+        Guard(
+            Command::new(env!("CARGO_BIN_EXE_proxy"))
+                .args(["--listen-ip", "127.0.0.1"])
+                .args(["--listen-port", &self.port.to_string()])
+                .args(["--target-ip", "127.0.0.1"])
+                .args(["--target-port", &self.target.to_string()])
+                .args(["--client-drop", &self.loss.to_string()])
+                .args(["--server-drop", &self.loss.to_string()])
+                .args(["--client-delay", "0", "--server-delay", "0"])
+                .args([
+                    "--client-delay-time-min",
+                    "0",
+                    "--client-delay-time-max",
+                    "0",
+                ])
+                .args([
+                    "--server-delay-time-min",
+                    "0",
+                    "--server-delay-time-max",
+                    "0",
+                ])
+                .args(["--log-port", &self.log_port.to_string()])
+                .args(["--log-file", self.log_file.to_str().unwrap()])
+                .args(["--seed", &self.seed.to_string()])
+                .arg("--headless")
+                .args(proxy_args)
+                .stdout(Stdio::null())
+                .spawn()
+                .unwrap(),
+        )
+    }
+}
+
+/// A headless proxy dropping `loss` of the datagrams in each direction, and a server behind it
+struct Lab {
+    dir: PathBuf,
+    proxy_port: u16,
+    log_port: u16,
+    log_file: PathBuf,
+    _proxy: Guard,
+    server: Guard,
+    lines: mpsc::Receiver<String>,
+}
+
+/// A client pushing MESSAGES lines, prefixed with its name
+struct Client {
+    child: Child,
+    summary: PathBuf,
+}
+
+impl Client {
+    fn finish(self) -> (Output, serde_json::Value) {
+        let output = self.child.wait_with_output().unwrap();
+        let summary = serde_json::from_slice(&std::fs::read(&self.summary).unwrap_or_default())
+            .unwrap_or(serde_json::Value::Null);
+        (output, summary)
+    }
+}
+
+impl Lab {
+    fn start(name: &str, loss: f64, seed: u64, server_args: &[&str]) -> Lab {
+        let dir = scratch_dir(name);
+        let proxy_port = udp_port();
+        let server_port = udp_port();
+        let log_port = tcp_port();
+        let log_file = dir.join("proxy.log");
+
+        let proxy = Proxy {
+            port: proxy_port,
+            target: server_port,
+            loss,
+            seed,
+            log_port,
+            log_file: log_file.clone(),
+        }
+        .spawn(&[]);
+        wait_for_tcp(log_port);
+
+        let mut server = Guard(
+            Command::new(env!("CARGO_BIN_EXE_server"))
+                .args(["--listen-ip", "127.0.0.1"])
+                .args(["--listen-port", &server_port.to_string()])
+                .args(["--log-host", "127.0.0.1"])
+                .args(["--log-port", &log_port.to_string()])
+                .args(server_args)
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+                .unwrap(),
+        );
+        let (lines_tx, lines) = mpsc::channel();
+        let stdout = server.0.stdout.take().unwrap();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                lines_tx.send(line).ok();
+            }
+        });
+        let ready = lines.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert!(ready.starts_with("Server listening"), "{}", ready);
+
+        Lab {
+            dir,
+            proxy_port,
+            log_port,
+            log_file,
+            _proxy: proxy,
+            server,
+            lines,
+        }
+    }
+
+    fn client(&self, name: &str, client_args: &[&str]) -> Client {
+        let input = self.dir.join(format!("{}.txt", name));
+        let messages: Vec<String> = (0..MESSAGES)
+            .map(|i| format!("{} line {}", name, i))
+            .collect();
+        std::fs::write(&input, messages.join("\n") + "\n").unwrap();
+        let summary = self.dir.join(format!("{}.json", name));
+
+        let child = Command::new(env!("CARGO_BIN_EXE_client"))
+            .args(["--target-ip", "127.0.0.1"])
+            .args(["--target-port", &self.proxy_port.to_string()])
+            .args(["--log-host", "127.0.0.1"])
+            .args(["--log-port", &self.log_port.to_string()])
+            .args(["--timeout", "1", "--min-rto-ms", "20"])
+            .args(["--input", input.to_str().unwrap()])
+            .args(["--summary", summary.to_str().unwrap()])
+            .arg("--batch")
+            .args(client_args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        Client { child, summary }
+    }
+
+    /// Stops the server; the messages it printed and the logged events
+    fn finish(self) -> (Vec<String>, Vec<LogEvent>) {
+        // let the last acks and log lines land before stopping everything
+        thread::sleep(Duration::from_millis(300));
+        drop(self.server);
+        let received = self
+            .lines
+            .try_iter()
+            .filter_map(|line| {
+                let text = line.strip_prefix("Got msg='")?;
+                Some(text[..text.rfind("' seq=")?].to_string())
+            })
+            .collect();
+
+        let events = std::fs::read_to_string(&self.log_file)
+            .unwrap()
+            .lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect();
+        (received, events)
+    }
+}
+
+/// One client through a lab of its own
+fn run(name: &str, loss: f64, seed: u64, client_args: &[&str]) -> Run {
+    let lab = Lab::start(name, loss, seed, &[]);
+    let (client, summary) = lab.client(name, client_args).finish();
+    let (received, events) = lab.finish();
+    Run {
+        client,
+        summary,
+        received,
+        events,
+    }
+}
+
+/// Every message arrived exactly once and every send is accounted for in the logs
+fn check(run: &Run, name: &str) {
+    assert!(
+        run.client.status.success(),
+        "client failed: {}",
+        String::from_utf8_lossy(&run.client.stderr)
+    );
+    assert_eq!(run.summary["delivered"], MESSAGES as u64);
+    assert_eq!(run.summary["failed"], 0);
+
+    let mut seen: HashMap<&str, usize> = HashMap::new();
+    for message in &run.received {
+        *seen.entry(message.as_str()).or_default() += 1;
+    }
+    assert_eq!(seen.len(), MESSAGES, "messages missing: {:?}", run.received);
+    assert!(seen.values().all(|n| *n == 1), "delivered twice");
+    assert!(seen.keys().all(|m| m.starts_with(name)));
+
+    let sends = run.count("client", "send");
+    assert_eq!(sends as u64, run.summary["transmissions"].as_u64().unwrap());
+    assert_eq!(run.count("client", "ack_recv"), MESSAGES);
+    assert_eq!(run.count("server", "recv"), run.count("server", "ack_send"));
+    assert_eq!(run.count("client", "close"), 1);
+}
+
+#[test]
+fn clean_link() {
+    let run = run("clean", 0.0, 1, &["--max-retries", "5"]);
+    check(&run, "clean");
+
+    assert_eq!(run.count("client", "send"), MESSAGES);
+    assert_eq!(run.count("server", "recv"), MESSAGES);
+    assert_eq!(run.count("proxy_client", "drop"), 0);
+    assert_eq!(run.summary["retransmissions"], 0);
+}
+
+#[test]
+fn lossy_link_retries() {
+    let run = run("lossy", 0.2, 2, &["--max-retries", "30"]);
+    check(&run, "lossy");
+
+    let drops = run.count("proxy_client", "drop") + run.count("proxy_server", "drop");
+    assert!(drops > 0);
+    assert!(run.count("client", "send") > MESSAGES);
+    assert!(run.summary["retransmissions"].as_u64().unwrap() > 0);
+    assert!(run.count("server", "recv") >= MESSAGES);
+}
+
+#[test]
+fn lossy_link_pipelined() {
+    let run = run(
+        "pipelined",
+        0.3,
+        3,
+        &[
+            "--max-retries",
+            "40",
+            "--window",
+            "8",
+            "--arq",
+            "selective-repeat",
+        ],
+    );
+    check(&run, "pipelined");
+
+    assert!(run.count("client", "send") > MESSAGES);
+    assert!(run.count("proxy_server", "drop") > 0);
+    // a lost ack makes the client resend what the server already has:
+    // acked again, but delivered only once (see check)
+    assert!(run.count("server", "recv") > MESSAGES);
+}
+
+#[test]
+fn clients_do_not_collide() {
+    let lab = Lab::start("pair", 0.1, 4, &[]);
+    let a = lab.client("alpha", &["--max-retries", "30"]);
+    let b = lab.client("beta", &["--max-retries", "30", "--window", "4"]);
+    let (a, a_summary) = a.finish();
+    let (b, b_summary) = b.finish();
+    let (received, events) = lab.finish();
+
+    for (client, summary) in [(a, a_summary), (b, b_summary)] {
+        assert!(client.status.success());
+        assert_eq!(summary["delivered"], MESSAGES as u64);
+        assert_eq!(summary["failed"], 0);
+    }
+    for name in ["alpha", "beta"] {
+        let mut mine: Vec<&String> = received.iter().filter(|m| m.starts_with(name)).collect();
+        assert_eq!(mine.len(), MESSAGES, "{}: {:?}", name, mine);
+        mine.sort();
+        mine.dedup();
+        assert_eq!(mine.len(), MESSAGES);
+    }
+
+    // both sessions were open at once, and both were closed again
+    let counts: Vec<f64> = events
+        .iter()
+        .filter(|e| e.component == "server" && e.event == "sessions")
+        .filter_map(|e| e.value)
+        .collect();
+    assert!(counts.contains(&2.0), "{:?}", counts);
+    assert_eq!(counts.last(), Some(&0.0));
+}
+
+#[test]
+fn idle_sessions_expire() {
+    let lab = Lab::start("idle", 0.0, 5, &["--idle-timeout-secs", "1"]);
+
+    // a client that opens a session and then vanishes without a FIN
+    let client = Guard(
+        Command::new(env!("CARGO_BIN_EXE_client"))
+            .args(["--target-ip", "127.0.0.1"])
+            .args(["--target-port", &lab.proxy_port.to_string()])
+            .args(["--log-host", "127.0.0.1"])
+            .args(["--log-port", &lab.log_port.to_string()])
+            .args(["--timeout", "1", "--max-retries", "5"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap(),
+    );
+    thread::sleep(Duration::from_millis(500));
+    drop(client);
+    thread::sleep(Duration::from_millis(2000));
+    let (_, events) = lab.finish();
+
+    let server: Vec<&LogEvent> = events.iter().filter(|e| e.component == "server").collect();
+    assert!(server.iter().any(|e| e.event == "expire"));
+    assert!(!server.iter().any(|e| e.event == "close"));
+    let last = server.iter().rev().find(|e| e.event == "sessions");
+    assert_eq!(last.and_then(|e| e.value), Some(0.0));
+}
+
+#[test]
+fn journal_holds_every_message_once() {
+    let journal = std::env::temp_dir().join(format!("loopback-journal-{}.bin", std::process::id()));
+    std::fs::remove_file(&journal).ok();
+    let lab = Lab::start("journal", 0.2, 6, &["--journal", journal.to_str().unwrap()]);
+    let (client, summary) = lab
+        .client("journal", &["--max-retries", "30", "--window", "4"])
+        .finish();
+    let (received, _) = lab.finish();
+
+    assert!(client.status.success());
+    assert_eq!(summary["delivered"], MESSAGES as u64);
+    let records = Journal::read(&journal).unwrap();
+    std::fs::remove_file(&journal).ok();
+    // the client said FIN, so its session is marked closed
+    assert!(matches!(records.last(), Some(JournalRecord::Closed { .. })));
+    let records: Vec<MessageRecord> = records
+        .into_iter()
+        .filter_map(|r| match r {
+            JournalRecord::Message(m) => Some(m),
+            _ => None,
+        })
+        .collect();
+
+    // only what was new: a resent message the server already had is not journaled again
+    let mut seqs: Vec<u64> = records.iter().map(|r| r.seq).collect();
+    seqs.sort();
+    seqs.dedup();
+    assert_eq!(seqs.len(), records.len());
+    let mut journaled: Vec<String> = records
+        .iter()
+        .map(|r| String::from_utf8(r.payload.clone()).unwrap())
+        .collect();
+    journaled.sort();
+    let mut received = received;
+    received.sort();
+    assert_eq!(journaled, received);
+    assert!(records.iter().all(|r| r.received_at > 0.0));
+}
+
+#[test]
+fn idle_clients_lose_their_upstream_socket() {
+    let dir = scratch_dir("proxy-idle");
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    server
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let proxy = Proxy {
+        port: udp_port(),
+        target: server.local_addr().unwrap().port(),
+        loss: 0.0,
+        seed: 1,
+        log_port: tcp_port(),
+        log_file: dir.join("proxy.log"),
+    };
+    let _guard = proxy.spawn(&["--idle-timeout-secs", "1"]);
+    wait_for_tcp(proxy.log_port);
+
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let mut buf = [0u8; 16];
+    let mut upstream = || {
+        client.send_to(b"ping", ("127.0.0.1", proxy.port)).unwrap();
+        server.recv_from(&mut buf).unwrap().1
+    };
+    let first = upstream();
+    assert_eq!(upstream(), first);
+    thread::sleep(Duration::from_millis(2500));
+    // the old socket (and its task) is gone, the client gets a new one
+    assert_ne!(upstream(), first);
+
+    let log = std::fs::read_to_string(&proxy.log_file).unwrap();
+    assert_eq!(log.matches("\"expire\"").count(), 1);
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn rates_too_small_for_a_duration_are_refused() {
+    for flag in ["--rate", "--pace"] {
+        let output = Command::new(env!("CARGO_BIN_EXE_client"))
+            .args(["--target", "127.0.0.1:9"])
+            .args(["--timeout", "1", "--max-retries", "1"])
+            .args(["--log-host", "127.0.0.1", "--log-port", "1"])
+            .args([flag, "1e-20", "--batch"])
+            .stdin(Stdio::null())
+            .output()
+            .unwrap();
+
+        assert!(!output.status.success());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let expected = format!("{} 1e-20 is too small", flag);
+        assert!(stderr.contains(&expected), "{}", stderr);
+    }
+}
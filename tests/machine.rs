@@ -0,0 +1,276 @@
+//! The protocol state machines on their own, fed datagrams and time by hand
+
+use final_project::machine::receiver::Action;
+use final_project::machine::{ReceiverMachine, SenderMachine, sender};
+use final_project::packet::{self, Ack, Message, Packet, decode, encode};
+use final_project::sender::Arq;
+use final_project::{Delivery, ReceiverConfig, SenderConfig};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+const EPOCH: u64 = 7;
+const ISN: u64 = 100;
+
+fn client() -> SocketAddr {
+    "127.0.0.1:40000".parse().unwrap()
+}
+
+fn drain(machine: &mut ReceiverMachine) -> Vec<Action> {
+    std::iter::from_fn(|| machine.poll_action()).collect()
+}
+
+/// A receiver with one established session from `client()`, and its id
+fn receiver(now: Instant) -> (ReceiverMachine, u64) {
+    let mut machine = ReceiverMachine::new(&ReceiverConfig::new("server"), 1, now);
+    let syn = Packet::Syn {
+        epoch: EPOCH,
+        isn: ISN,
+    };
+    machine.handle_datagram(now, client(), &encode(&syn));
+    let session = drain(&mut machine)
+        .into_iter()
+        .find_map(|action| match action {
+            Action::Send { datagram, .. } => match decode(&datagram) {
+                Some(Packet::SynAck { session, .. }) => Some(session),
+                _ => None,
+            },
+            _ => None,
+        })
+        .unwrap();
+    (machine, session)
+}
+
+fn fragment(session: u64, seq: u64, frag_index: u32, frag_count: u32) -> Vec<u8> {
+    encode(&Packet::Data(Message {
+        session,
+        epoch: EPOCH,
+        data: b"x".to_vec(),
+        seq,
+        frag_index,
+        frag_count,
+    }))
+}
+
+#[test]
+fn malformed_fragments_are_dropped_unacked() {
+    let now = Instant::now();
+    let (mut machine, session) = receiver(now);
+
+    for (seq, index, count) in [(3, 5, 8), (ISN, 0, u32::MAX), (ISN, 4, 4), (ISN, 2, 2000)] {
+        machine.handle_datagram(now, client(), &fragment(session, seq, index, count));
+        let actions = drain(&mut machine);
+        assert!(
+            actions.iter().all(|a| !matches!(a, Action::Send { .. })),
+            "{:?}",
+            actions
+        );
+        assert!(actions.iter().any(|a| matches!(
+            a,
+            Action::Log {
+                event: "malformed",
+                ..
+            }
+        )));
+    }
+
+    // the session still works
+    machine.handle_datagram(now, client(), &fragment(session, ISN, 0, 2));
+    machine.handle_datagram(now, client(), &fragment(session, ISN + 1, 1, 2));
+    let delivered = drain(&mut machine)
+        .into_iter()
+        .filter(|a| matches!(a, Action::Deliver(_)))
+        .count();
+    assert_eq!(delivered, 1);
+}
+
+#[test]
+fn a_duplicate_is_acked_again_but_not_delivered() {
+    let now = Instant::now();
+    let (mut machine, session) = receiver(now);
+    let mut acks_and_deliveries = || {
+        machine.handle_datagram(now, client(), &fragment(session, ISN, 0, 1));
+        let actions = drain(&mut machine);
+        let acked = actions.iter().any(|a| matches!(a,
+            Action::Send { datagram, .. } if matches!(decode(datagram), Some(Packet::Ack(ack)) if ack.cum == ISN)));
+        let delivered = actions
+            .iter()
+            .filter(|a| matches!(a, Action::Deliver(Delivery::Message { .. })))
+            .count();
+        (acked, delivered)
+    };
+
+    assert_eq!(acks_and_deliveries(), (true, 1));
+    // its ack got lost, so the client sends it again
+    assert_eq!(acks_and_deliveries(), (true, 0));
+}
+
+#[test]
+fn clients_going_down_and_up_are_told_apart() {
+    let start = Instant::now();
+    let mut config = ReceiverConfig::new("server");
+    config.heartbeat_interval = Some(Duration::from_secs(1));
+    config.heartbeat_misses = 2;
+    let mut machine = ReceiverMachine::new(&config, 1, start);
+    let addrs: [SocketAddr; 2] = [
+        "127.0.0.1:40000".parse().unwrap(),
+        "127.0.0.1:40001".parse().unwrap(),
+    ];
+    let mut sessions = Vec::new();
+    // two different clients, so two epochs
+    for (epoch, addr) in (EPOCH..).zip(addrs) {
+        let syn = Packet::Syn { epoch, isn: ISN };
+        machine.handle_datagram(start, addr, &encode(&syn));
+        sessions.extend(
+            drain(&mut machine)
+                .into_iter()
+                .find_map(|action| match action {
+                    Action::Send { datagram, .. } => match decode(&datagram) {
+                        Some(Packet::SynAck { session, .. }) => Some(session),
+                        _ => None,
+                    },
+                    _ => None,
+                }),
+        );
+    }
+    let events = |machine: &mut ReceiverMachine, event| -> Vec<u64> {
+        drain(machine)
+            .into_iter()
+            .filter_map(|action| match action {
+                Action::Log { event: e, seq, .. } if e == event => seq,
+                _ => None,
+            })
+            .collect()
+    };
+
+    let mut now = start;
+    for _ in 0..3 {
+        now += Duration::from_secs(1);
+        machine.handle_timeout(now);
+    }
+    let mut down = events(&mut machine, "peer_down");
+    down.sort();
+    let mut expected = sessions.clone();
+    expected.sort();
+    assert_eq!(down, expected);
+
+    let heartbeat = Packet::Heartbeat {
+        session: sessions[1],
+    };
+    machine.handle_datagram(now, addrs[1], &encode(&heartbeat));
+    assert_eq!(events(&mut machine, "peer_up"), [sessions[1]]);
+}
+
+const SESSION: u64 = 9;
+
+/// The datagrams a sender put on the wire, as (event, seq)
+fn sends(machine: &mut SenderMachine) -> Vec<(&'static str, u64)> {
+    std::iter::from_fn(|| machine.poll_action())
+        .filter_map(|action| match action {
+            sender::Action::Send { event, seq, .. } => Some((event, seq)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// A sender with its session established; the SYN-ACK came back at once,
+/// so its rto starts at the 50ms floor (and there is no backoff)
+fn sender(now: Instant) -> SenderMachine {
+    pipelined(now, 1, Arq::GoBackN)
+}
+
+/// `sender`, with `window` messages in flight
+fn pipelined(now: Instant, window: usize, arq: Arq) -> SenderMachine {
+    let mut config = SenderConfig::new("server");
+    config.window = window;
+    config.arq = arq;
+    let mut machine = SenderMachine::new(&config, EPOCH, ISN, [], 1, now);
+    let syn_ack = Packet::SynAck {
+        session: SESSION,
+        epoch: EPOCH,
+        isn: ISN,
+    };
+    machine.handle_datagram(now, &encode(&syn_ack));
+    sends(&mut machine);
+    assert!(machine.is_established());
+    machine
+}
+
+fn ack(seq: u64) -> Vec<u8> {
+    encode(&Packet::Ack(Ack {
+        session: SESSION,
+        seq,
+        cum: seq,
+        sack: 0,
+        failed: false,
+    }))
+}
+
+#[test]
+fn a_timeout_resends_the_message() {
+    let start = Instant::now();
+    let mut machine = sender(start);
+    machine.submit(start, packet::fragment(b"hello", 1000), None);
+    assert_eq!(sends(&mut machine), [("send", ISN)]);
+
+    // nothing happens before the timeout
+    let deadline = machine.poll_timeout().unwrap();
+    assert_eq!(deadline, start + Duration::from_millis(50));
+    machine.handle_timeout(deadline - Duration::from_millis(1));
+    assert!(sends(&mut machine).is_empty());
+
+    machine.handle_timeout(deadline);
+    assert_eq!(sends(&mut machine), [("send", ISN)]);
+    assert_eq!(machine.stats().transmissions, 2);
+    assert!(machine.poll_timeout().unwrap() > deadline);
+}
+
+#[test]
+fn only_messages_sent_once_are_rtt_samples() {
+    let start = Instant::now();
+    let mut machine = sender(start);
+
+    // Karn: an ack for a resent message could be for either copy
+    machine.submit(start, packet::fragment(b"resent", 1000), None);
+    sends(&mut machine);
+    let resent = machine.poll_timeout().unwrap();
+    machine.handle_timeout(resent);
+    assert_eq!(sends(&mut machine), [("send", ISN)]);
+    machine.handle_datagram(resent + Duration::from_millis(30), &ack(ISN));
+    sends(&mut machine);
+    assert!(machine.stats().rtt_samples.is_empty());
+
+    let sent = resent + Duration::from_millis(100);
+    machine.submit(sent, packet::fragment(b"once", 1000), None);
+    assert_eq!(sends(&mut machine), [("send", ISN + 1)]);
+    machine.handle_datagram(sent + Duration::from_millis(40), &ack(ISN + 1));
+    assert_eq!(machine.stats().rtt_samples, [Duration::from_millis(40)]);
+}
+
+#[test]
+fn go_back_n_only_trusts_cumulative_acks() {
+    let start = Instant::now();
+    // ISN + 1 is lost, ISN + 2 arrives and is sacked
+    let sacked = encode(&Packet::Ack(Ack {
+        session: SESSION,
+        seq: ISN + 2,
+        cum: ISN,
+        sack: 0b10,
+        failed: false,
+    }));
+    let resent = |arq| {
+        let mut machine = pipelined(start, 4, arq);
+        for text in [b"a", b"b", b"c"] {
+            machine.submit(start, packet::fragment(text, 1000), None);
+        }
+        assert_eq!(sends(&mut machine).len(), 3);
+        machine.handle_datagram(start, &ack(ISN));
+        machine.handle_datagram(start, &sacked);
+        sends(&mut machine);
+        let deadline = machine.poll_timeout().unwrap();
+        machine.handle_timeout(deadline);
+        sends(&mut machine)
+    };
+
+    assert_eq!(resent(Arq::GoBackN), [("send", ISN + 1), ("send", ISN + 2)]);
+    assert_eq!(resent(Arq::SelectiveRepeat), [("send", ISN + 1)]);
+}
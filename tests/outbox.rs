@@ -0,0 +1,41 @@
+//! The client's durable outbox
+
+use final_project::outbox::{Outbox, OutboxRecord};
+use final_project::packet::fragment;
+
+#[tokio::test]
+async fn reopening_compacts_to_what_is_pending() {
+    let dir = std::env::temp_dir().join(format!("outbox-{}", std::process::id()));
+    std::fs::remove_dir_all(&dir).ok();
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("pending.json");
+    // a file of the user's that only differs in its extension
+    let neighbour = dir.join("pending.tmp");
+    std::fs::write(&neighbour, "keep me").unwrap();
+
+    let mut outbox = Outbox::open(&path).await.unwrap();
+    assert!(outbox.pending.is_empty());
+    outbox
+        .append(&OutboxRecord::Open { epoch: 9 }, true)
+        .await
+        .unwrap();
+    for (seq, payload) in (10..13).zip(fragment(b"abc", 1)) {
+        let record = OutboxRecord::Enqueue { seq, payload };
+        outbox.append(&record, true).await.unwrap();
+    }
+    outbox
+        .append(&OutboxRecord::Ack { seq: 10 }, true)
+        .await
+        .unwrap();
+    drop(outbox);
+
+    let outbox = Outbox::open(&path).await.unwrap();
+    assert_eq!(outbox.epoch, Some(9));
+    assert_eq!(outbox.pending.keys().copied().collect::<Vec<_>>(), [11, 12]);
+    assert_eq!(outbox.next_seq, Some(13));
+    drop(outbox);
+    assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 3);
+    assert_eq!(std::fs::read_to_string(&neighbour).unwrap(), "keep me");
+    assert!(!dir.join("pending.json.tmp").exists());
+    std::fs::remove_dir_all(&dir).ok();
+}
@@ -0,0 +1,41 @@
+//! The token bucket pacing outgoing datagrams, on tokio's paused clock
+
+use final_project::pacer::TokenBucket;
+use std::time::Duration;
+
+#[tokio::test(start_paused = true)]
+async fn a_full_bucket_lets_a_burst_through() {
+    let mut bucket = TokenBucket::new(10.0, 3.0);
+    for _ in 0..3 {
+        assert_eq!(bucket.reserve(), Duration::ZERO);
+    }
+    // then one token per 100ms, queued up behind each other
+    assert_eq!(bucket.reserve(), Duration::from_millis(100));
+    assert_eq!(bucket.reserve(), Duration::from_millis(200));
+}
+
+#[tokio::test(start_paused = true)]
+async fn tokens_come_back_at_the_rate() {
+    let mut bucket = TokenBucket::new(10.0, 1.0);
+    assert_eq!(bucket.reserve(), Duration::ZERO);
+    tokio::time::advance(Duration::from_millis(50)).await;
+    assert_eq!(bucket.reserve(), Duration::from_millis(50));
+    tokio::time::advance(Duration::from_millis(150)).await;
+    assert_eq!(bucket.reserve(), Duration::ZERO);
+}
+
+#[tokio::test(start_paused = true)]
+async fn an_idle_bucket_holds_at_most_a_burst() {
+    let mut bucket = TokenBucket::new(100.0, 2.0);
+    tokio::time::advance(Duration::from_secs(10)).await;
+    assert_eq!(bucket.reserve(), Duration::ZERO);
+    assert_eq!(bucket.reserve(), Duration::ZERO);
+    assert_eq!(bucket.reserve(), Duration::from_millis(10));
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_rate_too_low_to_wait_for_never_gives_a_token() {
+    let mut bucket = TokenBucket::new(1e-20, 1.0);
+    assert_eq!(bucket.reserve(), Duration::ZERO);
+    assert_eq!(bucket.reserve(), Duration::MAX);
+}
@@ -0,0 +1,74 @@
+//! Backoff and jitter between resends
+
+use final_project::retry::{Backoff, Jitter, RetryPolicy};
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use std::time::Duration;
+
+const RTO: Duration = Duration::from_millis(100);
+
+fn policy(backoff: Backoff, jitter: Jitter) -> RetryPolicy {
+    RetryPolicy {
+        backoff,
+        jitter,
+        cap: Duration::from_secs(1),
+        floor: Duration::from_millis(50),
+    }
+}
+
+fn delays(policy: &RetryPolicy) -> Vec<Duration> {
+    let mut rng = StdRng::seed_from_u64(1);
+    let mut prev = RTO;
+    (1..=6)
+        .map(|tries| {
+            prev = policy.delay(RTO, tries, prev, &mut rng);
+            prev
+        })
+        .collect()
+}
+
+fn ms(n: u64) -> Duration {
+    Duration::from_millis(n)
+}
+
+#[test]
+fn fixed_waits_one_rto() {
+    assert_eq!(delays(&policy(Backoff::Fixed, Jitter::None)), [ms(100); 6]);
+}
+
+#[test]
+fn linear_grows_by_one_rto_per_try() {
+    assert_eq!(
+        delays(&policy(Backoff::Linear, Jitter::None)),
+        [ms(200), ms(300), ms(400), ms(500), ms(600), ms(700)]
+    );
+}
+
+#[test]
+fn exponential_doubles_up_to_the_cap() {
+    assert_eq!(
+        delays(&policy(Backoff::Exponential, Jitter::None)),
+        [ms(200), ms(400), ms(800), ms(1000), ms(1000), ms(1000)]
+    );
+}
+
+#[test]
+fn full_jitter_stays_between_the_floor_and_the_backoff() {
+    let jittered = delays(&policy(Backoff::Exponential, Jitter::Full));
+    let plain = delays(&policy(Backoff::Exponential, Jitter::None));
+    for (jittered, plain) in jittered.into_iter().zip(plain) {
+        assert!(jittered >= ms(50) && jittered <= plain, "{:?}", jittered);
+    }
+}
+
+#[test]
+fn decorrelated_jitter_stays_between_the_rto_and_the_cap() {
+    let policy = policy(Backoff::Fixed, Jitter::Decorrelated);
+    let delays = delays(&policy);
+    assert!(
+        delays.iter().all(|d| *d >= RTO && *d <= ms(1000)),
+        "{:?}",
+        delays
+    );
+    assert!(delays.iter().any(|d| *d > RTO));
+}
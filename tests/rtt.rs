@@ -0,0 +1,49 @@
+//! The retransmission timeout estimator (RFC 6298)
+
+use final_project::rtt::RttEstimator;
+use std::time::Duration;
+
+fn ms(n: u64) -> Duration {
+    Duration::from_millis(n)
+}
+
+#[test]
+fn starts_at_the_initial_timeout() {
+    assert_eq!(
+        RttEstimator::new(ms(1000), ms(50), ms(60_000)).rto(),
+        ms(1000)
+    );
+    assert_eq!(RttEstimator::new(ms(10), ms(50), ms(60_000)).rto(), ms(50));
+}
+
+#[test]
+fn first_sample_sets_srtt_and_half_of_it_as_variance() {
+    let mut rtt = RttEstimator::new(ms(1000), ms(50), ms(60_000));
+    rtt.sample(ms(100));
+    // 100 + 4 * 50
+    assert_eq!(rtt.rto(), ms(300));
+}
+
+#[test]
+fn later_samples_are_smoothed() {
+    let mut rtt = RttEstimator::new(ms(1000), ms(1), ms(60_000));
+    rtt.sample(ms(100));
+    rtt.sample(ms(200));
+    // srtt = (7 * 100 + 200) / 8 = 112.5, rttvar = (3 * 50 + 100) / 4 = 62.5
+    assert_eq!(rtt.rto(), Duration::from_micros(362_500));
+
+    // a steady link converges on its rtt
+    for _ in 0..100 {
+        rtt.sample(ms(80));
+    }
+    assert!(rtt.rto() >= ms(80) && rtt.rto() < ms(85), "{:?}", rtt.rto());
+}
+
+#[test]
+fn stays_within_its_bounds() {
+    let mut rtt = RttEstimator::new(ms(1000), ms(200), ms(2000));
+    rtt.sample(ms(10));
+    assert_eq!(rtt.rto(), ms(200));
+    rtt.sample(ms(5000));
+    assert_eq!(rtt.rto(), ms(2000));
+}
@@ -0,0 +1,269 @@
+//! Whole client / proxy / server runs on the simulator's virtual clock
+
+use final_project::impair::Impairment;
+use final_project::sender::Arq;
+use final_project::sim::{SimConfig, Simulation};
+use std::time::Duration;
+
+fn lossy(client_drop: f64, server_drop: f64, seed: u64) -> SimConfig {
+    let mut config = SimConfig::new(seed);
+    config.client_to_server.drop = client_drop;
+    config.server_to_client.drop = server_drop;
+    config.sender.max_retries = 40;
+    config
+}
+
+fn messages(n: usize) -> Vec<String> {
+    (0..n).map(|i| format!("msg {}", i)).collect()
+}
+
+fn bytes(messages: &[String]) -> Vec<Vec<u8>> {
+    messages.iter().map(|m| m.as_bytes().to_vec()).collect()
+}
+
+#[test]
+fn clean_link_delivers_everything_once() {
+    let sent = messages(100);
+    let outcome = Simulation::new(SimConfig::new(1)).unwrap().run(&sent);
+
+    assert!(outcome.finished);
+    assert_eq!(outcome.delivered, bytes(&sent));
+    assert_eq!(outcome.stats.delivered, 100);
+    assert_eq!(outcome.stats.transmissions, 100);
+    assert!(outcome.receipts.iter().all(|r| matches!(r, Some(Ok(_)))));
+}
+
+#[test]
+fn stop_and_wait_survives_heavy_loss_in_order() {
+    let sent = messages(1000);
+    let outcome = Simulation::new(lossy(0.3, 0.2, 7)).unwrap().run(&sent);
+
+    assert!(outcome.finished);
+    assert_eq!(outcome.delivered, bytes(&sent));
+    assert_eq!(outcome.stats.failed, 0);
+    assert!(outcome.dropped > 0);
+    assert!(outcome.stats.transmissions > 1000);
+}
+
+#[test]
+fn selective_repeat_with_reordering_delivers_exactly_once() {
+    let mut config = lossy(0.3, 0.2, 11);
+    config.sender.window = 16;
+    config.sender.arq = Arq::SelectiveRepeat;
+    let delay = Impairment {
+        drop: 0.3,
+        delay: 0.5,
+        delay_min: 1,
+        delay_max: 40,
+    };
+    config.client_to_server = delay;
+    config.server_to_client = Impairment { drop: 0.2, ..delay };
+
+    let sent = messages(1000);
+    let outcome = Simulation::new(config).unwrap().run(&sent);
+
+    assert!(outcome.finished);
+    let mut delivered = outcome.delivered.clone();
+    delivered.sort();
+    let mut expected = bytes(&sent);
+    expected.sort();
+    assert_eq!(delivered, expected);
+}
+
+#[test]
+fn ordered_mode_holds_early_messages_until_the_gap_is_filled() {
+    let mut config = lossy(0.2, 0.2, 13);
+    config.sender.window = 16;
+    config.sender.arq = Arq::SelectiveRepeat;
+    config.client_to_server.delay = 0.5;
+    config.client_to_server.delay_min = 1;
+    config.client_to_server.delay_max = 40;
+    config.receiver.ordered = true;
+
+    let sent = messages(500);
+    let outcome = Simulation::new(config).unwrap().run(&sent);
+
+    assert!(outcome.finished);
+    assert_eq!(outcome.delivered, bytes(&sent));
+    let count = |event| outcome.events.iter().filter(|e| e.event == event).count();
+    assert!(count("gap") > 0);
+    assert_eq!(count("skip"), 0);
+    assert_eq!(count("deliver"), sent.len());
+}
+
+#[test]
+fn ordered_mode_with_no_reorder_buffer_skips_gaps_at_once() {
+    let mut config = lossy(0.2, 0.2, 13);
+    config.sender.window = 16;
+    config.sender.arq = Arq::SelectiveRepeat;
+    config.client_to_server.delay = 0.5;
+    config.client_to_server.delay_min = 1;
+    config.client_to_server.delay_max = 40;
+    config.receiver.ordered = true;
+    config.receiver.reorder_limit = 0;
+
+    let outcome = Simulation::new(config).unwrap().run(messages(500));
+
+    assert!(outcome.finished);
+    let count = |event| outcome.events.iter().filter(|e| e.event == event).count();
+    assert_eq!(count("gap"), 0);
+    assert!(count("skip") > 0);
+}
+
+#[test]
+fn ordered_mode_drops_what_turns_up_after_its_gap_was_skipped() {
+    let mut config = lossy(0.3, 0.2, 17);
+    config.sender.window = 16;
+    config.sender.arq = Arq::SelectiveRepeat;
+    config.client_to_server.delay = 0.5;
+    config.client_to_server.delay_min = 1;
+    config.client_to_server.delay_max = 40;
+    config.receiver.ordered = true;
+    config.receiver.reorder_limit = 4;
+
+    let sent = messages(500);
+    let outcome = Simulation::new(config).unwrap().run(&sent);
+
+    assert!(outcome.finished);
+    let count = |event| outcome.events.iter().filter(|e| e.event == event).count();
+    assert!(count("late") > 0);
+    // every message is either handed out, in order, or dropped as late
+    assert_eq!(count("deliver") + count("late"), sent.len());
+    let delivered: Vec<u64> = outcome
+        .events
+        .iter()
+        .filter(|e| e.event == "deliver")
+        .filter_map(|e| e.seq)
+        .collect();
+    assert!(delivered.windows(2).all(|w| w[0] < w[1]));
+}
+
+#[test]
+fn long_run_past_the_dedup_window_delivers_exactly_once() {
+    let mut config = lossy(0.2, 0.3, 12);
+    config.sender.window = 32;
+    config.sender.arq = Arq::SelectiveRepeat;
+
+    let sent = messages(5000);
+    let outcome = Simulation::new(config).unwrap().run(&sent);
+
+    assert!(outcome.finished);
+    let mut delivered = outcome.delivered.clone();
+    delivered.sort();
+    let mut expected = bytes(&sent);
+    expected.sort();
+    assert_eq!(delivered, expected);
+}
+
+#[test]
+fn window_larger_than_the_dedup_window_is_capped() {
+    let mut config = lossy(0.2, 0.2, 14);
+    config.sender.window = 2000;
+    config.sender.arq = Arq::SelectiveRepeat;
+
+    let sent = messages(4000);
+    let outcome = Simulation::new(config).unwrap().run(&sent);
+
+    assert!(outcome.finished);
+    assert!(outcome.receipts.iter().all(|r| matches!(r, Some(Ok(_)))));
+    let mut delivered = outcome.delivered.clone();
+    delivered.sort();
+    let mut expected = bytes(&sent);
+    expected.sort();
+    assert_eq!(delivered, expected);
+}
+
+#[test]
+fn go_back_n_with_congestion_control_delivers_everything() {
+    let mut config = lossy(0.1, 0.1, 5);
+    config.sender.window = 32;
+    config.sender.cc = final_project::congestion::Cc::Aimd;
+
+    let sent = messages(500);
+    let outcome = Simulation::new(config).unwrap().run(&sent);
+
+    assert!(outcome.finished);
+    let mut delivered = outcome.delivered.clone();
+    delivered.sort();
+    let mut expected = bytes(&sent);
+    expected.sort();
+    assert_eq!(delivered, expected);
+    assert!(outcome.events.iter().any(|e| e.event == "cwnd"));
+}
+
+#[test]
+fn long_messages_are_reassembled() {
+    let mut config = lossy(0.2, 0.2, 3);
+    config.sender.mtu = 200;
+    let sent: Vec<String> = (0..20).map(|i| format!("{} ", i).repeat(100)).collect();
+    let outcome = Simulation::new(config).unwrap().run(&sent);
+
+    assert!(outcome.finished);
+    assert_eq!(outcome.delivered, bytes(&sent));
+    assert!(
+        outcome
+            .receipts
+            .iter()
+            .all(|r| matches!(r, Some(Ok(r)) if r.fragments > 1))
+    );
+    // messages, not fragments
+    let summary = outcome.stats.summary();
+    assert_eq!(summary.delivered, sent.len() as u64);
+    assert_eq!(summary.failed, 0);
+    assert!(outcome.stats.payloads > sent.len() as u64);
+    assert_eq!(
+        summary.retransmissions,
+        outcome.stats.transmissions - outcome.stats.payloads
+    );
+}
+
+#[test]
+fn exhausted_messages_are_given_up() {
+    let mut config = SimConfig::new(9);
+    config.sender.max_retries = 2;
+    // the handshake gets through, after that nothing reaches the server
+    let mut sim = Simulation::new(config).unwrap();
+    while !sim.can_submit() {
+        assert!(sim.step());
+    }
+    let blackhole = Impairment {
+        drop: 1.0,
+        ..Impairment::default()
+    };
+    sim.set_impairments(blackhole, Impairment::default());
+    sim.submit(b"lost");
+    sim.close();
+    while !sim.is_finished() && sim.step() {}
+    let outcome = sim.outcome();
+
+    assert!(outcome.finished);
+    assert!(outcome.delivered.is_empty());
+    assert_eq!(outcome.stats.failed, 1);
+    assert!(matches!(outcome.receipts[0], Some(Err(_))));
+    assert!(outcome.events.iter().any(|e| e.event == "give_up"));
+}
+
+#[test]
+fn same_seed_same_run() {
+    let run = |seed| {
+        let outcome = Simulation::new(lossy(0.3, 0.2, seed))
+            .unwrap()
+            .run(messages(200));
+        (outcome.elapsed, outcome.stats.transmissions, outcome.events)
+    };
+
+    assert_eq!(run(21), run(21));
+    assert_ne!(run(21).1, run(22).1);
+}
+
+#[test]
+fn runs_on_virtual_time() {
+    let mut config = lossy(0.3, 0.2, 4);
+    config.latency = Duration::from_millis(50);
+    let started = std::time::Instant::now();
+    let outcome = Simulation::new(config).unwrap().run(messages(200));
+
+    assert!(outcome.finished);
+    assert!(outcome.elapsed > Duration::from_secs(20));
+    assert!(started.elapsed() < outcome.elapsed);
+}